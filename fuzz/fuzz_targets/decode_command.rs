@@ -0,0 +1,25 @@
+#![no_main]
+use garage_controller::aes;
+use garage_controller::controller::decode_command;
+use garage_controller::jwt::JWTService;
+use garage_controller::provision::generate_key_pair;
+use lazy_static::lazy_static;
+use libfuzzer_sys::fuzz_target;
+
+const AES_KEY: &str = "546191f3-ac70-43c3-b9ad-a26d8fds";
+
+lazy_static! {
+    static ref JWT_SVC_VERIF: JWTService =
+        JWTService::new(generate_key_pair(2048).unwrap().1, None);
+}
+
+fuzz_target!(|data: &[u8]| {
+    // raw payload as received from broker
+    let _ = decode_command(data, AES_KEY, &JWT_SVC_VERIF);
+
+    // fuzzer would hardly produce valid ciphertext, encrypt input to get to token verification
+    if let Ok(token) = std::str::from_utf8(data) {
+        let payload = aes::encrypt(token, AES_KEY).unwrap();
+        let _ = decode_command(payload.as_bytes(), AES_KEY, &JWT_SVC_VERIF);
+    }
+});
@@ -0,0 +1,15 @@
+#![no_main]
+use garage_controller::aes;
+use libfuzzer_sys::fuzz_target;
+
+const AES_KEY: &str = "546191f3-ac70-43c3-b9ad-a26d8fds";
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(text) = std::str::from_utf8(data) {
+        let _ = aes::decrypt(text, AES_KEY);
+        // key is validated at startup, but decrypt must not panic even with wrong one
+        if let Some((key, text)) = text.split_once('\n') {
+            let _ = aes::decrypt(text, key);
+        }
+    }
+});
@@ -4,6 +4,24 @@ use crypto::{aes, blockmodes, buffer};
 use hex;
 use rand::RngCore;
 
+/// AES-256 key length in bytes, key from configuration is used as is (i.e. 32 characters)
+pub const KEY_LEN: usize = 32;
+
+/// CBC initialization vector length in bytes (AES block size)
+const IV_LEN: usize = 16;
+
+/// checks length of configured key, cipher would panic with key of wrong size
+pub fn check_key(key: &str) -> Result<()> {
+    if key.len() != KEY_LEN {
+        return Err(Error::new(format!(
+            "aes key must be {} bytes long, got {}",
+            KEY_LEN,
+            key.len()
+        )));
+    }
+    Ok(())
+}
+
 /// for implementation details see https://github.com/DaGenix/rust-crypto/blob/master/examples/symmetriccipher.rs#L17
 fn encrypt_impl(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
     let mut encryptor =
@@ -22,7 +40,7 @@ fn encrypt_impl(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
                 .take_read_buffer()
                 .take_remaining()
                 .iter()
-                .map(|&i| i),
+                .copied(),
         );
 
         match result {
@@ -51,7 +69,7 @@ fn decrypt_impl(encrypted_data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>>
                 .take_read_buffer()
                 .take_remaining()
                 .iter()
-                .map(|&i| i),
+                .copied(),
         );
         match result {
             BufferResult::BufferUnderflow => break,
@@ -63,20 +81,24 @@ fn decrypt_impl(encrypted_data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>>
 }
 
 pub fn encrypt(text: &str, encryption_key: &str) -> Result<String> {
+    check_key(encryption_key)?;
     let key = encryption_key.as_bytes();
     let data_to_encrypt = text.as_bytes();
-    let mut iv: [u8; 16] = [0; 16];
+    let mut iv: [u8; IV_LEN] = [0; IV_LEN];
 
-    let mut rng = rand::rngs::OsRng::default();
+    let mut rng = rand::rngs::OsRng;
     rng.fill_bytes(&mut iv);
 
-    let encrypted_data = encrypt_impl(data_to_encrypt, &key, &iv)?;
+    let encrypted_data = encrypt_impl(data_to_encrypt, key, &iv)?;
     let strigified_data = hex::encode(encrypted_data);
-    let iv = hex::encode(&iv);
+    let iv = hex::encode(iv);
     Ok(format!("{}:{}", iv, strigified_data))
 }
 
+/// decrypts payload received from broker, malformed payload (or key) results in error,
+/// cipher would panic on key or iv of wrong size
 pub fn decrypt(text: &str, encryption_key: &str) -> Result<String> {
+    check_key(encryption_key)?;
     let split: Vec<&str> = text.split(":").collect();
     if split.len() != 2 {
         return Err(Error::new(
@@ -84,6 +106,13 @@ pub fn decrypt(text: &str, encryption_key: &str) -> Result<String> {
         ));
     }
     let iv = hex::decode(split[0])?;
+    if iv.len() != IV_LEN {
+        return Err(Error::new(format!(
+            "iv must be {} bytes long, got {}",
+            IV_LEN,
+            iv.len()
+        )));
+    }
     let encrypted_text = hex::decode(split[1])?;
 
     let decrytped_text = decrypt_impl(&encrypted_text[..], encryption_key.as_bytes(), &iv[..])?;
@@ -94,6 +123,8 @@ pub fn decrypt(text: &str, encryption_key: &str) -> Result<String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    // rand::RngCore is already imported by super
+    use proptest::prelude::{any, prop, prop_assert_eq, proptest};
 
     // cargo test -- --show-output test_encrypt_impl_decrypt_impl
     #[test]
@@ -103,7 +134,7 @@ mod tests {
         let mut key: [u8; 32] = [0; 32];
         let mut iv: [u8; 16] = [0; 16];
 
-        let mut rng = rand::rngs::OsRng::default();
+        let mut rng = rand::rngs::OsRng;
 
         rng.fill_bytes(&mut key);
         rng.fill_bytes(&mut iv);
@@ -129,4 +160,57 @@ mod tests {
 
         Ok(())
     }
+
+    // cargo test -- --show-output test_check_key
+    #[test]
+    fn test_check_key() {
+        assert!(check_key("546191f3-ac70-43c3-b9ad-a26d8fds").is_ok());
+        assert!(check_key("546191f3-ac70-43c3-b9ad").is_err());
+        assert!(check_key("").is_err());
+    }
+
+    // cargo test -- --show-output test_decrypt_wrong_iv
+    #[test]
+    fn test_decrypt_wrong_iv() {
+        let secret = "546191f3-ac70-43c3-b9ad-a26d8fds";
+        let result = decrypt("00:00112233445566778899aabbccddeeff", secret);
+        assert!(result
+            .unwrap_err()
+            .message
+            .contains("iv must be 16 bytes long"));
+        assert!(decrypt(":", secret).is_err());
+        assert!(encrypt("text", "short key").is_err());
+        assert!(decrypt("00:00", "short key").is_err());
+    }
+
+    proptest! {
+        // cargo test -- --show-output test_round_trip
+        #[test]
+        fn test_round_trip(text in any::<String>(), key in "[ -~]{32}") {
+            let encrypted = encrypt(&text, &key).unwrap();
+            prop_assert_eq!(decrypt(&encrypted, &key).unwrap(), text);
+        }
+
+        // cargo test -- --show-output test_decrypt_arbitrary_input
+        #[test]
+        fn test_decrypt_arbitrary_input(text in any::<String>(), key in any::<String>()) {
+            let _ = decrypt(&text, &key);
+        }
+
+        // cargo test -- --show-output test_decrypt_arbitrary_hex
+        #[test]
+        fn test_decrypt_arbitrary_hex(iv in "[0-9a-f]{0,40}", data in "[0-9a-f]{0,200}") {
+            let _ = decrypt(&format!("{}:{}", iv, data), "546191f3-ac70-43c3-b9ad-a26d8fds");
+        }
+
+        // cargo test -- --show-output test_decrypt_corrupted_payload
+        #[test]
+        fn test_decrypt_corrupted_payload(text in ".{0,100}", idx in any::<prop::sample::Index>(), digit in "[0-9a-f:]") {
+            let secret = "546191f3-ac70-43c3-b9ad-a26d8fds";
+            let mut encrypted = encrypt(&text, secret).unwrap();
+            let idx = idx.index(encrypted.len());
+            encrypted.replace_range(idx..=idx, &digit);
+            let _ = decrypt(&encrypted, secret);
+        }
+    }
 }
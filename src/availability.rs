@@ -0,0 +1,302 @@
+use crate::errors::{Error, Result};
+use crate::toml::MQTT;
+use bytes::BytesMut;
+use log::{debug, info, warn};
+use mqttrs::{
+    decode, encode, Connect, ConnectReturnCode, LastWill, Packet, Protocol, Publish, QoS, QosPid,
+};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{delay_for, interval, timeout, Duration};
+
+/// payload of availability message while controller is running
+pub const ONLINE: &str = "online";
+/// payload of availability message published on shutdown or by broker as last will
+pub const OFFLINE: &str = "offline";
+
+/// broker publishes last will when no packet arrives within 1.5 times keep alive
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+#[cfg(not(test))]
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+#[cfg(test)]
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Separate MQTT connection announcing availability of the controller by retained
+/// online/offline message. mqtt-async-client cannot register last will, this connection
+/// registers offline message as last will so that broker publishes it when controller
+/// disappears (power cut, crash, network failure). Online message is published after
+/// every (re)connect.
+pub struct Availability {
+    stop_tx: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+    /// reconnects not yet taken, see take_reconnects
+    reconnects: Arc<AtomicUsize>,
+}
+
+/// connection settings, copied so that background task does not borrow configuration
+#[derive(Clone)]
+struct Settings {
+    host: String,
+    port: u16,
+    username: String,
+    password: String,
+    topic: String,
+}
+
+impl Availability {
+    /// connects in background task which keeps reconnecting until stopped,
+    /// must be called within tokio runtime
+    pub fn start(config: &MQTT) -> Self {
+        let settings = Settings {
+            host: config.host.clone(),
+            port: config.port,
+            username: config.username.clone(),
+            password: config.password.clone(),
+            topic: config.availability_topic.clone(),
+        };
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let reconnects = Arc::new(AtomicUsize::new(0));
+        let task = tokio::spawn(run(settings, stop_rx, reconnects.clone()));
+        Availability {
+            stop_tx,
+            task,
+            reconnects,
+        }
+    }
+
+    /// number of times this connection was lost and re-established since last call.
+    /// mqtt-async-client reconnects command connection silently, so these reconnects
+    /// are only a proxy for interruptions of the command connection.
+    pub fn take_reconnects(&self) -> usize {
+        self.reconnects.swap(0, Ordering::SeqCst)
+    }
+
+    /// publishes offline message and disconnects so that broker discards last will.
+    /// Dropping `Availability` without stop closes the connection and broker publishes
+    /// last will instead.
+    pub async fn stop(self) {
+        let _ = self.stop_tx.send(());
+        let _ = self.task.await;
+    }
+}
+
+async fn run(settings: Settings, mut stop_rx: oneshot::Receiver<()>, reconnects: Arc<AtomicUsize>) {
+    let mut connected = false;
+    loop {
+        match session(&settings, &mut stop_rx, &reconnects, &mut connected).await {
+            Ok(()) => return,
+            Err(err) => warn!(
+                "availability connection to {}:{} failed: {}, reconnecting in {:?}",
+                settings.host, settings.port, err, RETRY_DELAY
+            ),
+        }
+        tokio::select! {
+            _ = delay_for(RETRY_DELAY) => {}
+            _ = &mut stop_rx => return,
+        }
+    }
+}
+
+/// single connection, returns Ok once stopped. Connection is counted as reconnect
+/// if there was successful connection before.
+async fn session(
+    settings: &Settings,
+    stop_rx: &mut oneshot::Receiver<()>,
+    reconnects: &AtomicUsize,
+    connected: &mut bool,
+) -> Result<()> {
+    let mut stream = TcpStream::connect((settings.host.as_str(), settings.port)).await?;
+    let (mut reader, mut writer) = stream.split();
+    let mut buffer = BytesMut::with_capacity(1024);
+
+    send(
+        &mut writer,
+        &Packet::Connect(Connect {
+            protocol: Protocol::MQTT311,
+            keep_alive: KEEP_ALIVE.as_secs() as u16,
+            client_id: format!("garage-controller-availability-{}", process::id()),
+            clean_session: true,
+            last_will: Some(LastWill {
+                topic: settings.topic.clone(),
+                message: OFFLINE.as_bytes().to_vec(),
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
+            username: Some(settings.username.clone()),
+            password: Some(settings.password.as_bytes().to_vec()),
+        }),
+    )
+    .await?;
+    let connack = timeout(KEEP_ALIVE, read(&mut reader, &mut buffer))
+        .await
+        .map_err(|_| Error::new("no connack received".to_owned()))??;
+    match connack {
+        Packet::Connack(connack) if connack.code == ConnectReturnCode::Accepted => {}
+        Packet::Connack(connack) => {
+            return Err(Error::new(format!(
+                "connection refused: {:?}",
+                connack.code
+            )))
+        }
+        packet => return Err(Error::new(format!("unexpected packet {:?}", packet))),
+    }
+    send(&mut writer, &availability(&settings.topic, ONLINE)).await?;
+    info!("{} published on {}", ONLINE, settings.topic);
+    if *connected {
+        reconnects.fetch_add(1, Ordering::SeqCst);
+    }
+    *connected = true;
+
+    let mut ping = interval(KEEP_ALIVE / 2);
+    let mut last_received = Instant::now();
+    loop {
+        tokio::select! {
+            stopped = &mut *stop_rx => {
+                // sender dropped without stop, leave it up to last will
+                if stopped.is_ok() {
+                    send(&mut writer, &availability(&settings.topic, OFFLINE)).await?;
+                    send(&mut writer, &Packet::Disconnect).await?;
+                    info!("{} published on {}", OFFLINE, settings.topic);
+                }
+                return Ok(());
+            }
+            _ = ping.tick() => {
+                if last_received.elapsed() > KEEP_ALIVE {
+                    return Err(Error::new("server does not respond".to_owned()));
+                }
+                send(&mut writer, &Packet::Pingreq).await?;
+            }
+            packet = read(&mut reader, &mut buffer) => {
+                let packet = packet?;
+                debug!("availability connection received {:?}", packet);
+                last_received = Instant::now();
+            }
+        }
+    }
+}
+
+fn availability(topic: &str, payload: &str) -> Packet {
+    Packet::Publish(Publish {
+        dup: false,
+        qospid: QosPid::AtMostOnce,
+        retain: true,
+        topic_name: topic.to_owned(),
+        payload: payload.as_bytes().to_vec(),
+    })
+}
+
+async fn send<W: AsyncWrite + Unpin>(writer: &mut W, packet: &Packet) -> Result<()> {
+    // encoder does not grow the buffer
+    let mut buffer = BytesMut::with_capacity(1024);
+    encode(packet, &mut buffer)?;
+    writer.write_all(&buffer).await?;
+    Ok(())
+}
+
+/// reads next packet, data read so far are kept in buffer so that cancelled read loses nothing
+async fn read<R: AsyncRead + Unpin>(reader: &mut R, buffer: &mut BytesMut) -> Result<Packet> {
+    let mut chunk = [0u8; 1024];
+    loop {
+        if let Some(packet) = decode(buffer)? {
+            return Ok(packet);
+        }
+        let read = reader.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(Error::new("connection closed by server".to_owned()));
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker::Broker;
+    use crate::mqtt;
+
+    fn config(broker: &Broker) -> MQTT {
+        MQTT {
+            host: broker.host(),
+            port: broker.port(),
+            username: "user".to_owned(),
+            password: "pass".to_owned(),
+            availability_topic: mqtt::AVAILABILITY_TOPIC.to_owned(),
+        }
+    }
+
+    /// waits until retained availability message has given payload
+    async fn wait_for(broker: &Broker, payload: &str) -> Result<()> {
+        for _ in 0..50 {
+            if let Some(message) = broker.retained(mqtt::AVAILABILITY_TOPIC) {
+                if message.payload == payload.as_bytes() {
+                    return Ok(());
+                }
+            }
+            delay_for(Duration::from_millis(20)).await;
+        }
+        Err(Error::new(format!("{} not published", payload)))
+    }
+
+    // cargo test -- --show-output test_online_and_offline
+    #[test]
+    fn test_online_and_offline() -> Result<()> {
+        let mut rt = tokio::runtime::Runtime::new()?;
+        rt.block_on(async {
+            let broker = Broker::start().await?;
+            let availability = Availability::start(&config(&broker));
+            wait_for(&broker, ONLINE).await?;
+            availability.stop().await;
+            wait_for(&broker, OFFLINE).await?;
+            assert!(broker.retained(mqtt::AVAILABILITY_TOPIC).unwrap().retain);
+            // disconnect discards last will, i.e. offline is not published twice
+            delay_for(Duration::from_millis(100)).await;
+            assert_eq!(broker.published().len(), 2);
+            Ok(())
+        })
+    }
+
+    // cargo test -- --show-output test_last_will
+    #[test]
+    fn test_last_will() -> Result<()> {
+        let mut rt = tokio::runtime::Runtime::new()?;
+        rt.block_on(async {
+            let broker = Broker::start().await?;
+            // controller which dies does not publish anything, offline is last will
+            drop(Availability::start(&config(&broker)));
+            wait_for(&broker, OFFLINE).await?;
+            let published: Vec<Vec<u8>> = broker
+                .published()
+                .into_iter()
+                .map(|message| message.payload)
+                .collect();
+            assert_eq!(published, vec![ONLINE.as_bytes(), OFFLINE.as_bytes()]);
+            Ok(())
+        })
+    }
+
+    // cargo test -- --show-output test_online_after_reconnect
+    #[test]
+    fn test_online_after_reconnect() -> Result<()> {
+        let mut rt = tokio::runtime::Runtime::new()?;
+        rt.block_on(async {
+            let broker = Broker::start().await?;
+            let availability = Availability::start(&config(&broker));
+            wait_for(&broker, ONLINE).await?;
+            assert_eq!(availability.take_reconnects(), 0);
+            broker.drop_connections();
+            wait_for(&broker, OFFLINE).await?;
+            // reconnect publishes online again
+            wait_for(&broker, ONLINE).await?;
+            assert_eq!(availability.take_reconnects(), 1);
+            assert_eq!(availability.take_reconnects(), 0);
+            availability.stop().await;
+            Ok(())
+        })
+    }
+}
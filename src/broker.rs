@@ -0,0 +1,300 @@
+use bytes::BytesMut;
+use log::debug;
+use mqttrs::{
+    decode, encode, Connack, ConnectReturnCode, LastWill, Packet, Publish, QoS, QosPid, Suback,
+    SubscribeReturnCodes,
+};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::oneshot;
+
+/// message published by any client
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+struct Session {
+    id: usize,
+    subscriptions: Vec<String>,
+    tx: UnboundedSender<Packet>,
+    /// published when connection is lost without disconnect packet
+    last_will: Option<LastWill>,
+    /// closes the connection, see Broker::drop_connections
+    drop_tx: Option<oneshot::Sender<()>>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: usize,
+    sessions: Vec<Session>,
+    retained: BTreeMap<String, Message>,
+    published: Vec<Message>,
+}
+
+/// Minimal in-process MQTT 3.1.1 broker so that tests do not need real (cloud) broker.
+/// Supports connect, subscribe (with + and # wildcards), unsubscribe, publish with QoS 0 and 1
+/// (delivered with QoS 0), retained messages, last will and ping. Credentials are not checked.
+/// Broker listens on random local port and runs until runtime which started it is dropped.
+pub struct Broker {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl Broker {
+    /// binds 127.0.0.1 on random port, must be called within tokio runtime
+    pub async fn start() -> io::Result<Broker> {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(State::default()));
+        let accept_state = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, accept_state.clone()));
+            }
+        });
+        debug!("test broker listening on {}", addr);
+        Ok(Broker { addr, state })
+    }
+
+    pub fn host(&self) -> String {
+        self.addr.ip().to_string()
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    /// all messages published so far, in order of arrival
+    pub fn published(&self) -> Vec<Message> {
+        self.state.lock().unwrap().published.clone()
+    }
+
+    /// retained message of given topic
+    pub fn retained(&self, topic: &str) -> Option<Message> {
+        self.state.lock().unwrap().retained.get(topic).cloned()
+    }
+
+    /// closes all client connections as if network failed, last wills are published
+    pub fn drop_connections(&self) {
+        for session in self.state.lock().unwrap().sessions.iter_mut() {
+            if let Some(drop_tx) = session.drop_tx.take() {
+                let _ = drop_tx.send(());
+            }
+        }
+    }
+}
+
+/// true if topic matches subscription filter, e.g. garage/+ or garage/#
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut topic_levels = topic.split('/');
+    for filter_level in filter.split('/') {
+        match (filter_level, topic_levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (filter_level, Some(topic_level)) if filter_level == topic_level => {}
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+async fn serve(stream: TcpStream, state: Arc<Mutex<State>>) {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (tx, mut rx) = unbounded_channel::<Packet>();
+    let (drop_tx, mut drop_rx) = oneshot::channel::<()>();
+
+    let id = {
+        let mut state = state.lock().unwrap();
+        state.next_id += 1;
+        let id = state.next_id;
+        state.sessions.push(Session {
+            id,
+            subscriptions: vec![],
+            tx: tx.clone(),
+            last_will: None,
+            drop_tx: Some(drop_tx),
+        });
+        id
+    };
+
+    tokio::spawn(async move {
+        while let Some(packet) = rx.recv().await {
+            // encoder does not grow the buffer
+            let payload_len = match &packet {
+                Packet::Publish(publish) => publish.topic_name.len() + publish.payload.len(),
+                _ => 0,
+            };
+            let mut buffer = BytesMut::with_capacity(payload_len + 1024);
+            if encode(&packet, &mut buffer).is_err() || writer.write_all(&buffer).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut buffer = BytesMut::with_capacity(4096);
+    let mut chunk = [0u8; 4096];
+    let mut disconnected = false;
+    'connection: loop {
+        let read = tokio::select! {
+            read = reader.read(&mut chunk) => read,
+            _ = &mut drop_rx => break,
+        };
+        let read = match read {
+            Ok(0) | Err(_) => break,
+            Ok(read) => read,
+        };
+        buffer.extend_from_slice(&chunk[..read]);
+        loop {
+            match decode(&mut buffer) {
+                Ok(Some(Packet::Disconnect)) => {
+                    disconnected = true;
+                    break 'connection;
+                }
+                Err(_) => break 'connection,
+                Ok(Some(packet)) => handle(id, packet, &tx, &state),
+                Ok(None) => break,
+            }
+        }
+    }
+
+    debug!("test broker client {} disconnected", id);
+    let mut state = state.lock().unwrap();
+    let idx = state.sessions.iter().position(|session| session.id == id);
+    let session = state.sessions.remove(idx.unwrap());
+    if let (false, Some(last_will)) = (disconnected, session.last_will) {
+        store_and_deliver(
+            &mut state,
+            Message {
+                topic: last_will.topic,
+                payload: last_will.message,
+                retain: last_will.retain,
+            },
+        );
+    }
+}
+
+fn handle(id: usize, packet: Packet, tx: &UnboundedSender<Packet>, state: &Mutex<State>) {
+    let mut state = state.lock().unwrap();
+    match packet {
+        Packet::Connect(connect) => {
+            if let Some(session) = state.sessions.iter_mut().find(|session| session.id == id) {
+                session.last_will = connect.last_will;
+            }
+            let _ = tx.send(Packet::Connack(Connack {
+                session_present: false,
+                code: ConnectReturnCode::Accepted,
+            }));
+        }
+        Packet::Pingreq => {
+            let _ = tx.send(Packet::Pingresp);
+        }
+        Packet::Subscribe(subscribe) => {
+            let filters: Vec<String> = subscribe
+                .topics
+                .iter()
+                .map(|topic| topic.topic_path.clone())
+                .collect();
+            let return_codes = subscribe
+                .topics
+                .iter()
+                .map(|topic| match topic.qos {
+                    QoS::AtMostOnce => SubscribeReturnCodes::Success(QoS::AtMostOnce),
+                    _ => SubscribeReturnCodes::Success(QoS::AtLeastOnce),
+                })
+                .collect();
+            let _ = tx.send(Packet::Suback(Suback {
+                pid: subscribe.pid,
+                return_codes,
+            }));
+            for message in state.retained.values() {
+                if filters
+                    .iter()
+                    .any(|filter| topic_matches(filter, &message.topic))
+                {
+                    let _ = tx.send(deliver(message, true));
+                }
+            }
+            if let Some(session) = state.sessions.iter_mut().find(|session| session.id == id) {
+                session.subscriptions.extend(filters);
+            }
+        }
+        Packet::Unsubscribe(unsubscribe) => {
+            if let Some(session) = state.sessions.iter_mut().find(|session| session.id == id) {
+                session
+                    .subscriptions
+                    .retain(|filter| !unsubscribe.topics.contains(filter));
+            }
+            let _ = tx.send(Packet::Unsuback(unsubscribe.pid));
+        }
+        Packet::Publish(publish) => {
+            if let QosPid::AtLeastOnce(pid) = publish.qospid {
+                let _ = tx.send(Packet::Puback(pid));
+            }
+            let message = Message {
+                topic: publish.topic_name,
+                payload: publish.payload,
+                retain: publish.retain,
+            };
+            store_and_deliver(&mut state, message);
+        }
+        packet => debug!("test broker ignoring {:?}", packet),
+    }
+}
+
+/// stores retained message and delivers it to subscribers
+fn store_and_deliver(state: &mut State, message: Message) {
+    if message.retain {
+        if message.payload.is_empty() {
+            state.retained.remove(&message.topic);
+        } else {
+            state
+                .retained
+                .insert(message.topic.clone(), message.clone());
+        }
+    }
+    for session in &state.sessions {
+        if session
+            .subscriptions
+            .iter()
+            .any(|filter| topic_matches(filter, &message.topic))
+        {
+            let _ = session.tx.send(deliver(&message, false));
+        }
+    }
+    state.published.push(message);
+}
+
+fn deliver(message: &Message, retain: bool) -> Packet {
+    Packet::Publish(Publish {
+        dup: false,
+        qospid: QosPid::AtMostOnce,
+        retain,
+        topic_name: message.topic.clone(),
+        payload: message.payload.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // cargo test -- --show-output test_topic_matches
+    #[test]
+    fn test_topic_matches() {
+        assert!(topic_matches("garage/toggle", "garage/toggle"));
+        assert!(!topic_matches("garage/toggle", "garage/toggleConfirm"));
+        assert!(topic_matches("garage/+", "garage/status"));
+        assert!(!topic_matches("garage/+", "garage/status/left"));
+        assert!(topic_matches("garage/#", "garage/status/left"));
+        assert!(topic_matches("#", "gate/toggle"));
+        assert!(!topic_matches("garage/toggle/left", "garage/toggle"));
+    }
+}
@@ -0,0 +1,128 @@
+use crate::toml::GPIO;
+use std::time::{Duration, Instant};
+
+/// press recognized by button state machine
+#[derive(Debug, PartialEq)]
+pub enum Press {
+    /// button released before long press time elapsed
+    Short,
+    /// button held for long press time, reported once while button is still held
+    Long,
+}
+
+/// Debounces raw button level and recognizes short and long presses.
+/// Level change is accepted only after it is stable for debounce time.
+pub struct Button {
+    debounce: Duration,
+    long_press: Duration,
+    /// last raw level and time it was first seen
+    raw: (bool, Instant),
+    /// debounced level
+    pressed: bool,
+    pressed_since: Instant,
+    long_reported: bool,
+}
+
+impl Button {
+    pub fn new(config: &GPIO, now: Instant) -> Self {
+        Button {
+            debounce: Duration::from_millis(config.debounce_ms),
+            long_press: Duration::from_millis(config.long_press_ms),
+            raw: (false, now),
+            pressed: false,
+            pressed_since: now,
+            long_reported: false,
+        }
+    }
+
+    /// feeds current raw level of button, returns recognized press if any
+    pub fn update(&mut self, level: bool, now: Instant) -> Option<Press> {
+        if level != self.raw.0 {
+            self.raw = (level, now);
+        }
+        let stable = now.saturating_duration_since(self.raw.1) >= self.debounce;
+
+        if stable && level != self.pressed {
+            self.pressed = level;
+            if !level {
+                // release after long press was already reported is not a short press
+                return if self.long_reported {
+                    None
+                } else {
+                    Some(Press::Short)
+                };
+            }
+            self.pressed_since = self.raw.1;
+            self.long_reported = false;
+        }
+
+        if self.pressed
+            && !self.long_reported
+            && now.saturating_duration_since(self.pressed_since) >= self.long_press
+        {
+            self.long_reported = true;
+            return Some(Press::Long);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(start: Instant, millis: u64) -> Instant {
+        start + Duration::from_millis(millis)
+    }
+
+    // cargo test -- --show-output test_short_press
+    #[test]
+    fn test_short_press() {
+        let start = Instant::now();
+        let mut button = Button::new(&GPIO::default(), start);
+
+        assert_eq!(button.update(true, ms(start, 0)), None);
+        assert_eq!(button.update(true, ms(start, 60)), None);
+        assert_eq!(button.update(false, ms(start, 300)), None);
+        assert_eq!(button.update(false, ms(start, 360)), Some(Press::Short));
+        assert_eq!(button.update(false, ms(start, 400)), None);
+    }
+
+    // cargo test -- --show-output test_bounce_ignored
+    #[test]
+    fn test_bounce_ignored() {
+        let start = Instant::now();
+        let mut button = Button::new(&GPIO::default(), start);
+
+        // contact bouncing shorter than debounce time is not a press
+        for millis in (0..200).step_by(20) {
+            let level = millis % 40 == 0;
+            assert_eq!(button.update(level, ms(start, millis)), None);
+        }
+        assert_eq!(button.update(false, ms(start, 300)), None);
+
+        // noise while pressed does not release the button
+        assert_eq!(button.update(true, ms(start, 400)), None);
+        assert_eq!(button.update(true, ms(start, 460)), None);
+        assert_eq!(button.update(false, ms(start, 500)), None);
+        assert_eq!(button.update(true, ms(start, 520)), None);
+        assert_eq!(button.update(true, ms(start, 600)), None);
+        assert_eq!(button.update(false, ms(start, 700)), None);
+        assert_eq!(button.update(false, ms(start, 750)), Some(Press::Short));
+    }
+
+    // cargo test -- --show-output test_long_press
+    #[test]
+    fn test_long_press() {
+        let start = Instant::now();
+        let mut button = Button::new(&GPIO::default(), start);
+
+        assert_eq!(button.update(true, ms(start, 0)), None);
+        assert_eq!(button.update(true, ms(start, 1999)), None);
+        assert_eq!(button.update(true, ms(start, 2000)), Some(Press::Long));
+        assert_eq!(button.update(true, ms(start, 3000)), None);
+        // release after long press does not produce short press
+        assert_eq!(button.update(false, ms(start, 3100)), None);
+        assert_eq!(button.update(false, ms(start, 3200)), None);
+    }
+}
@@ -1,14 +1,53 @@
-use clap::{App, Arg, ArgMatches};
+use crate::provision::ProvisionOptions;
+use crate::selftest::RelayTestOptions;
+use crate::simulator::SendOptions;
+use crate::tools::Signer;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// action requested on command line
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// run the controller (default)
+    Run,
+    /// validate configuration and referenced files, then exit
+    CheckConfig,
+    /// encrypt text (signed token) by configured aes key
+    Encrypt { input: Option<String> },
+    /// decrypt payload by configured aes key
+    Decrypt { input: Option<String> },
+    /// sign claims (JSON object) by microcontroller private key
+    Sign { input: Option<String> },
+    /// verify token by public key of smart home or microcontroller
+    Verify {
+        input: Option<String>,
+        signer: Signer,
+        validate_expiry: bool,
+    },
+    /// decrypt and decode captured payload, report which key verifies it
+    Inspect { input: Option<String> },
+    /// act as smart home, send command and wait for confirmation
+    Send(SendOptions),
+    /// generate keys and write configuration (given by --config-file) referring to them
+    Provision(ProvisionOptions),
+    /// pulse relay without broker and check sensors
+    TestRelay(RelayTestOptions),
+}
 
 #[derive(Debug)]
 pub struct CommandLine<'a> {
     pub app_config_path: &'a Path,
+    pub command: Command,
 }
 
 impl<'a> CommandLine<'a> {
-    fn new(app_config_path: &'a Path) -> Self {
-        CommandLine { app_config_path }
+    fn new(app_config_path: &'a Path, command: Command) -> Self {
+        CommandLine {
+            app_config_path,
+            command,
+        }
     }
 }
 
@@ -17,6 +56,7 @@ pub fn get_cmd_line_parser<'a, 'b>() -> App<'a, 'b> {
         .version("v0.1.1")
         .author("Adam Bezecny")
         .about("Raspberry Pi Software for controlling garage door via MQTT and GPIO")
+        .setting(AppSettings::VersionlessSubcommands)
         .arg(
             Arg::with_name("app_config_path")
                 .short("f")
@@ -26,10 +66,469 @@ pub fn get_cmd_line_parser<'a, 'b>() -> App<'a, 'b> {
                 .takes_value(true)
                 .required(true),
         )
+        .subcommand(
+            SubCommand::with_name("check-config")
+                .about("Validates configuration and key files it references, prints all problems and exits"),
+        )
+        .subcommand(
+            SubCommand::with_name("encrypt")
+                .about("Encrypts text (e.g. signed token) by configured AES key")
+                .arg(input_arg("Text to encrypt")),
+        )
+        .subcommand(
+            SubCommand::with_name("decrypt")
+                .about("Decrypts payload (<<iv>>:<<data>>) by configured AES key")
+                .arg(input_arg("Payload to decrypt")),
+        )
+        .subcommand(
+            SubCommand::with_name("sign")
+                .about("Signs claims by microcontroller private key, missing claims are defaulted")
+                .arg(input_arg("Claims as JSON object, e.g. {\"command\":\"toggle\",\"id\":\"1\"}")),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Verifies token and prints its claims")
+                .arg(input_arg("Token to verify"))
+                .arg(
+                    Arg::with_name("signer")
+                        .long("signer")
+                        .value_name("SIGNER")
+                        .help("Owner of the key which signed the token")
+                        .possible_values(&["smart-home", "microcontroller"])
+                        .default_value("smart-home"),
+                )
+                .arg(
+                    Arg::with_name("ignore_expiry")
+                        .long("ignore-expiry")
+                        .help("Accepts expired token"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("inspect")
+                .about("Decrypts and decodes payload captured on MQTT broker, reports which key verifies it")
+                .arg(input_arg("Payload or token to inspect")),
+        )
+        .subcommand(
+            SubCommand::with_name("send")
+                .about("Acts as smart home: sends signed and encrypted command, waits for and verifies confirmation")
+                .arg(
+                    Arg::with_name("command")
+                        .value_name("COMMAND")
+                        .help("Command to send")
+                        .possible_values(&[
+                            "toggle", "open", "close", "lock", "unlock", "status", "history",
+                        ])
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("private_key")
+                        .short("k")
+                        .long("private-key")
+                        .value_name("FILE")
+                        .help("Smart home private key used to sign the command")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("door")
+                        .long("door")
+                        .value_name("ID")
+                        .help("Target door id")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("flag")
+                        .long("flag")
+                        .value_name("FLAG")
+                        .help("Flag of the command (e.g. flag unlocking controller), can be repeated")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("page")
+                        .long("page")
+                        .value_name("PAGE")
+                        .help("Requested page of history command")
+                        .takes_value(true)
+                        .validator(is_number::<usize>),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .value_name("SECS")
+                        .help("How long to wait for confirmation")
+                        .takes_value(true)
+                        .default_value("10")
+                        .validator(is_number::<u64>),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("provision")
+                .about("Generates microcontroller key pair and AES key, writes configuration file (given by --config-file) referring to them")
+                .arg(
+                    Arg::with_name("key_dir")
+                        .long("key-dir")
+                        .value_name("DIR")
+                        .help("Directory where keys are written, directory of configuration file by default")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("smart_home_pub_key")
+                        .long("smart-home-pub-key")
+                        .value_name("FILE")
+                        .help("Smart home public key, if already available")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("bits")
+                        .long("bits")
+                        .value_name("BITS")
+                        .help("RSA key size")
+                        .possible_values(&["2048", "3072", "4096"])
+                        .default_value("2048"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Overwrites existing configuration and key files"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("test-relay")
+                .about("Pulses relay of configured door without MQTT broker and checks whether sensors report door movement")
+                .arg(
+                    Arg::with_name("door")
+                        .long("door")
+                        .value_name("ID")
+                        .help("Door to test, first door by default")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("pulses")
+                        .long("pulses")
+                        .value_name("COUNT")
+                        .help("Number of pulses")
+                        .takes_value(true)
+                        .default_value("1")
+                        .validator(is_count),
+                )
+                .arg(
+                    Arg::with_name("wait")
+                        .long("wait")
+                        .value_name("SECS")
+                        .help("How long to wait for sensors after each pulse, travel_secs of the door by default")
+                        .takes_value(true)
+                        .validator(is_number::<u64>),
+                )
+                .arg(
+                    Arg::with_name("yes")
+                        .short("y")
+                        .long("yes")
+                        .help("Pulses without confirmation prompts"),
+                ),
+        )
+}
+
+/// optional positional input of tool subcommands, stdin is read if missing or '-'
+fn input_arg<'a, 'b>(help: &'b str) -> Arg<'a, 'b> {
+    Arg::with_name("input")
+        .value_name("INPUT")
+        .help(help)
+        .index(1)
+}
+
+/// value must parse into the type of the option, so that parsed value can be unwrapped
+fn is_number<T: FromStr>(value: String) -> Result<(), String> {
+    value
+        .parse::<T>()
+        .map(|_| ())
+        .map_err(|_| format!("{} is not a number", value))
+}
+
+fn is_count(value: String) -> Result<(), String> {
+    match value.parse::<u32>() {
+        Ok(0) => Err("must be at least 1".to_owned()),
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("{} is not a number", value)),
+    }
 }
 
 pub fn get_cmdl_options<'a>(matches: &'a ArgMatches) -> CommandLine<'a> {
+    let command = match matches.subcommand() {
+        ("check-config", _) => Command::CheckConfig,
+        ("encrypt", Some(sub)) => Command::Encrypt { input: input(sub) },
+        ("decrypt", Some(sub)) => Command::Decrypt { input: input(sub) },
+        ("sign", Some(sub)) => Command::Sign { input: input(sub) },
+        ("verify", Some(sub)) => Command::Verify {
+            input: input(sub),
+            signer: match sub.value_of("signer") {
+                Some("microcontroller") => Signer::Microcontroller,
+                _ => Signer::SmartHome,
+            },
+            validate_expiry: !sub.is_present("ignore_expiry"),
+        },
+        ("inspect", Some(sub)) => Command::Inspect { input: input(sub) },
+        ("send", Some(sub)) => Command::Send(SendOptions {
+            command: sub.value_of("command").unwrap().to_owned(),
+            door: sub.value_of("door").map(|door| door.to_owned()),
+            flags: sub
+                .values_of("flag")
+                .map_or(vec![], |flags| flags.map(|flag| flag.to_owned()).collect()),
+            // numbers are checked by validators already
+            page: sub.value_of("page").map(|page| page.parse().unwrap()),
+            private_key: sub.value_of("private_key").unwrap().to_owned(),
+            timeout: Duration::from_secs(sub.value_of("timeout").unwrap().parse().unwrap()),
+        }),
+        ("provision", Some(sub)) => Command::Provision(ProvisionOptions {
+            key_dir: sub.value_of("key_dir").map(|dir| dir.to_owned()),
+            smart_home_pub_key: sub.value_of("smart_home_pub_key").map(|key| key.to_owned()),
+            bits: sub.value_of("bits").unwrap().parse().unwrap(),
+            force: sub.is_present("force"),
+        }),
+        ("test-relay", Some(sub)) => Command::TestRelay(RelayTestOptions {
+            door: sub.value_of("door").map(|door| door.to_owned()),
+            pulses: sub.value_of("pulses").unwrap().parse().unwrap(),
+            wait: sub
+                .value_of("wait")
+                .map(|wait| Duration::from_secs(wait.parse().unwrap())),
+            yes: sub.is_present("yes"),
+        }),
+        _ => Command::Run,
+    };
     let app_config_path = Path::new(matches.value_of("app_config_path").unwrap());
 
-    CommandLine::new(app_config_path)
+    CommandLine::new(app_config_path, command)
+}
+
+fn input(matches: &ArgMatches) -> Option<String> {
+    matches.value_of("input").map(|input| input.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // cargo test -- --show-output test_run_by_default
+    #[test]
+    fn test_run_by_default() {
+        let matches =
+            get_cmd_line_parser().get_matches_from(vec!["garage-controller", "-f", "app.toml"]);
+        let cmd_line = get_cmdl_options(&matches);
+        assert_eq!(cmd_line.command, Command::Run);
+        assert_eq!(cmd_line.app_config_path, Path::new("app.toml"));
+    }
+
+    // cargo test -- --show-output test_check_config
+    #[test]
+    fn test_check_config() {
+        let matches = get_cmd_line_parser().get_matches_from(vec![
+            "garage-controller",
+            "--config-file",
+            "app.toml",
+            "check-config",
+        ]);
+        let cmd_line = get_cmdl_options(&matches);
+        assert_eq!(cmd_line.command, Command::CheckConfig);
+        assert_eq!(cmd_line.app_config_path, Path::new("app.toml"));
+    }
+
+    // cargo test -- --show-output test_tool_commands
+    #[test]
+    fn test_tool_commands() {
+        let matches = get_cmd_line_parser().get_matches_from(vec![
+            "garage-controller",
+            "-f",
+            "app.toml",
+            "decrypt",
+            "00:11",
+        ]);
+        assert_eq!(
+            get_cmdl_options(&matches).command,
+            Command::Decrypt {
+                input: Some("00:11".to_owned())
+            }
+        );
+
+        let matches = get_cmd_line_parser().get_matches_from(vec![
+            "garage-controller",
+            "-f",
+            "app.toml",
+            "verify",
+            "--signer",
+            "microcontroller",
+            "--ignore-expiry",
+        ]);
+        assert_eq!(
+            get_cmdl_options(&matches).command,
+            Command::Verify {
+                input: None,
+                signer: Signer::Microcontroller,
+                validate_expiry: false,
+            }
+        );
+
+        let matches = get_cmd_line_parser().get_matches_from(vec![
+            "garage-controller",
+            "-f",
+            "app.toml",
+            "verify",
+            "token",
+        ]);
+        assert_eq!(
+            get_cmdl_options(&matches).command,
+            Command::Verify {
+                input: Some("token".to_owned()),
+                signer: Signer::SmartHome,
+                validate_expiry: true,
+            }
+        );
+
+        let result = get_cmd_line_parser().get_matches_from_safe(vec![
+            "garage-controller",
+            "-f",
+            "app.toml",
+            "verify",
+            "--signer",
+            "somebody",
+        ]);
+        assert!(result.is_err());
+    }
+
+    // cargo test -- --show-output test_send
+    #[test]
+    fn test_send() {
+        let matches = get_cmd_line_parser().get_matches_from(vec![
+            "garage-controller",
+            "-f",
+            "app.toml",
+            "send",
+            "unlock",
+            "-k",
+            "smart-home-priv.pem",
+            "--door",
+            "gate",
+            "--flag",
+            "pin_confirmed",
+            "--flag",
+            "other",
+        ]);
+        assert_eq!(
+            get_cmdl_options(&matches).command,
+            Command::Send(SendOptions {
+                command: "unlock".to_owned(),
+                door: Some("gate".to_owned()),
+                flags: vec!["pin_confirmed".to_owned(), "other".to_owned()],
+                page: None,
+                private_key: "smart-home-priv.pem".to_owned(),
+                timeout: Duration::from_secs(10),
+            })
+        );
+
+        let matches = get_cmd_line_parser().get_matches_from(vec![
+            "garage-controller",
+            "-f",
+            "app.toml",
+            "send",
+            "history",
+            "-k",
+            "smart-home-priv.pem",
+            "--page",
+            "2",
+            "--timeout",
+            "3",
+        ]);
+        match get_cmdl_options(&matches).command {
+            Command::Send(options) => {
+                assert_eq!(options.page, Some(2));
+                assert_eq!(options.timeout, Duration::from_secs(3));
+            }
+            command => panic!("unexpected command {:?}", command),
+        }
+
+        let result = get_cmd_line_parser().get_matches_from_safe(vec![
+            "garage-controller",
+            "-f",
+            "app.toml",
+            "send",
+            "history",
+            "-k",
+            "smart-home-priv.pem",
+            "--page",
+            "last",
+        ]);
+        assert!(result.is_err());
+    }
+
+    // cargo test -- --show-output test_provision
+    #[test]
+    fn test_provision() {
+        let matches = get_cmd_line_parser().get_matches_from(vec![
+            "garage-controller",
+            "-f",
+            "/etc/garage/app_config.toml",
+            "provision",
+            "--bits",
+            "4096",
+            "--force",
+        ]);
+        let cmd_line = get_cmdl_options(&matches);
+        assert_eq!(
+            cmd_line.command,
+            Command::Provision(ProvisionOptions {
+                key_dir: None,
+                smart_home_pub_key: None,
+                bits: 4096,
+                force: true,
+            })
+        );
+        assert_eq!(
+            cmd_line.app_config_path,
+            Path::new("/etc/garage/app_config.toml")
+        );
+    }
+
+    // cargo test -- --show-output test_test_relay
+    #[test]
+    fn test_test_relay() {
+        let matches = get_cmd_line_parser().get_matches_from(vec![
+            "garage-controller",
+            "-f",
+            "app.toml",
+            "test-relay",
+            "--door",
+            "gate",
+            "--pulses",
+            "2",
+            "--wait",
+            "20",
+            "-y",
+        ]);
+        assert_eq!(
+            get_cmdl_options(&matches).command,
+            Command::TestRelay(RelayTestOptions {
+                door: Some("gate".to_owned()),
+                pulses: 2,
+                wait: Some(Duration::from_secs(20)),
+                yes: true,
+            })
+        );
+    }
+
+    // cargo test -- --show-output test_invalid_numbers
+    #[test]
+    fn test_invalid_numbers() {
+        let parse = |args: &[&str]| {
+            let mut all = vec!["garage-controller", "-f", "app.toml"];
+            all.extend_from_slice(args);
+            get_cmd_line_parser().get_matches_from_safe(all)
+        };
+        // out of range of u32, must not panic once parsed
+        assert!(parse(&["test-relay", "--pulses", "5000000000"]).is_err());
+        assert!(parse(&["test-relay", "--pulses", "0"]).is_err());
+        assert!(parse(&["test-relay", "--pulses", "1"]).is_ok());
+        assert!(parse(&["send", "status", "-k", "key.pem", "--timeout", "-1"]).is_err());
+        assert!(parse(&["send", "history", "-k", "key.pem", "--page", "x"]).is_err());
+    }
 }
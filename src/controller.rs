@@ -0,0 +1,1436 @@
+use crate::aes;
+use crate::button::{Button, Press};
+use crate::door::{
+    check_target, AutoCloseAction, AutoCloseTimer, DoorState, MovementMonitor, MovementOutcome,
+    TargetCheck,
+};
+use crate::errors::Result;
+use crate::gpio;
+use crate::history::{History, HistoryEntry, HistoryReply};
+use crate::jwt::{Claims, JWTService};
+use crate::mqtt;
+use crate::policy::Policy;
+use crate::ratelimit::{Decision, RateLimiter};
+use crate::state::{self, StateStore};
+use crate::toml::{self, ApplicationConfiguration, Topics, UnknownStatePolicy};
+use chrono::Local;
+use log::{debug, error, info, warn};
+use mqtt_async_client::client::Client;
+use std::time::{Instant, SystemTime};
+use tokio::time::{delay_for, Duration};
+
+/// issuer of commands created by wall button, can be used in access policy rules
+pub const BUTTON_ISSUER: &str = "local-button";
+
+/// how often wall buttons are sampled
+const BUTTON_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// how often sensors are read while movements are drained during shutdown
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// reply to commands which will not be executed (or confirmed) because controller stops
+const SHUTDOWN_REPLY: &str = "shutting_down";
+
+/// Executes verified commands received from smart home and sends signed replies.
+/// Keys verifying incoming messages are owned by caller (see handle_message).
+/// Every door has its own relay, sensors, rate limiter and topics.
+pub struct Controller {
+    shared: Shared,
+    doors: Vec<DoorHandler>,
+}
+
+/// services and settings shared by all doors
+struct Shared {
+    jwt_svc_signing: JWTService,
+    policy: Policy,
+    queue_during_cooldown: bool,
+    two_phase_confirmation: bool,
+    unknown_state_policy: UnknownStatePolicy,
+    state: StateStore,
+    history: History,
+    history_page_size: usize,
+    unlock_issuer: Option<String>,
+    unlock_flag: Option<String>,
+}
+
+/// runtime state of single door
+struct DoorHandler {
+    id: String,
+    topics: Topics,
+    gpio: gpio::Gpio,
+    pulse_duration: Duration,
+    rate_limiter: RateLimiter,
+    /// command received during cooldown, executed once cooldown elapses
+    queued_command: Option<Claims>,
+    auto_close_timer: AutoCloseTimer,
+    movement_monitor: MovementMonitor,
+    /// id of command waiting for final completed/failed reply
+    awaiting_final_reply: Option<String>,
+    button: Option<Button>,
+    button_command: String,
+    long_press_command: String,
+}
+
+/// decrypts and verifies payload received on command topic. Payload is untrusted,
+/// any malformed input must result in error, never in panic.
+pub fn decode_command(payload: &[u8], aes_key: &str, jwt_svc_verif: &JWTService) -> Result<Claims> {
+    let payload = String::from_utf8(payload.to_vec())?;
+    debug!("original payload from mqtt {}", payload);
+
+    let decrypted_payload = aes::decrypt(&payload, aes_key)?;
+    debug!("decrypted payload from mqtt {}", decrypted_payload);
+
+    let claims = jwt_svc_verif.verify(&decrypted_payload, true)?;
+    debug!("token verified. claims {:#?}", claims);
+    Ok(claims)
+}
+
+impl Controller {
+    /// initializes gpio of all configured doors, relay pins are set to idle level
+    pub fn new(config: &ApplicationConfiguration, jwt_svc_signing: JWTService) -> Result<Self> {
+        let state = StateStore::load(&config.state.file)?;
+        let mut history = History::load(&config.history.file, config.history.max_entries)?;
+        history.record(HistoryEntry::new("startup"));
+        let mut doors = vec![];
+        for door in config.doors() {
+            let door = DoorHandler::new(config, door)?;
+            door.log_offline_change(&state);
+            doors.push(door);
+        }
+
+        Ok(Controller {
+            shared: Shared {
+                jwt_svc_signing,
+                policy: Policy::new(&config.policy)?,
+                queue_during_cooldown: config.rate_limit.queue_during_cooldown,
+                two_phase_confirmation: config.confirmation.two_phase,
+                unknown_state_policy: config.commands.unknown_state,
+                state,
+                history,
+                history_page_size: config.history.page_size,
+                unlock_issuer: config.lock.unlock_issuer.clone(),
+                unlock_flag: config.lock.unlock_flag.clone(),
+            },
+            doors,
+        })
+    }
+
+    /// applies reloaded configuration and signing keys. Policy and command handling settings
+    /// take effect immediately, doors, rate limit and auto close are kept until restart
+    /// (see ApplicationConfiguration::restart_required).
+    pub fn reload(
+        &mut self,
+        config: &ApplicationConfiguration,
+        jwt_svc_signing: JWTService,
+    ) -> Result<()> {
+        // everything which can fail is prepared first so that failed reload changes nothing
+        let policy = Policy::new(&config.policy)?;
+
+        let shared = &mut self.shared;
+        shared.jwt_svc_signing = jwt_svc_signing;
+        shared.policy = policy;
+        shared.queue_during_cooldown = config.rate_limit.queue_during_cooldown;
+        shared.two_phase_confirmation = config.confirmation.two_phase;
+        shared.unknown_state_policy = config.commands.unknown_state;
+        shared.unlock_issuer = config.lock.unlock_issuer.clone();
+        shared.unlock_flag = config.lock.unlock_flag.clone();
+        shared.history_page_size = config.history.page_size;
+        shared.history.record(HistoryEntry::new("reload"));
+        Ok(())
+    }
+
+    /// distinct command topics of all doors, i.e. topics to subscribe
+    pub fn command_topics(&self) -> Vec<String> {
+        let mut topics: Vec<String> = vec![];
+        for door in &self.doors {
+            if !topics.contains(&door.topics.command_topic) {
+                topics.push(door.topics.command_topic.clone());
+            }
+        }
+        topics
+    }
+
+    /// gpio of door with given id
+    pub fn gpio(&self, door_id: &str) -> Option<&gpio::Gpio> {
+        self.doors
+            .iter()
+            .find(|door| door.id == door_id)
+            .map(|door| &door.gpio)
+    }
+
+    /// records in history that availability connection to broker was interrupted and
+    /// re-established. Reconnects of the command connection are not observable (client
+    /// reconnects silently), this is only a proxy for them.
+    pub fn record_availability_reconnect(&mut self) {
+        warn!("availability connection to MQTT server was interrupted and re-established");
+        self.shared
+            .history
+            .record(HistoryEntry::new("availability_reconnect"));
+    }
+
+    /// decrypts and verifies message received on command topic and processes the command.
+    /// Message which cannot be decoded is logged and recorded in history only, anybody can
+    /// publish on command topic so it must not stop the controller.
+    pub async fn handle_message(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        aes_key: &str,
+        jwt_svc_verif: &JWTService,
+        c: &Client,
+    ) -> Result<()> {
+        let claims = match decode_command(payload, aes_key, jwt_svc_verif) {
+            Ok(claims) => claims,
+            Err(err) => {
+                warn!("invalid message received on {} ignored: {}", topic, err);
+                self.shared
+                    .history
+                    .record(HistoryEntry::new("invalid_message"));
+                return Ok(());
+            }
+        };
+        self.handle_command(topic, claims, c).await
+    }
+
+    /// processes command received on given topic. Target door is taken from door claim,
+    /// it can be omitted if there is only one door listening on the topic.
+    pub async fn handle_command(&mut self, topic: &str, claims: Claims, c: &Client) -> Result<()> {
+        // valid token captured from broker must not be usable again
+        if self.shared.state.state().is_replay(&claims.id) {
+            warn!("command {} rejected: already received", claims.id);
+            return Ok(());
+        }
+        self.shared.state.update(|state| {
+            state.remember_command(&claims.id, claims.exp, state::now());
+        })?;
+
+        // these commands are not bound to single door
+        if ["lock", "unlock", "status", "history"].contains(&claims.command.as_str()) {
+            return self.handle_controller_command(topic, claims, c).await;
+        }
+
+        let door_idx = self.route(topic, claims.door.as_deref());
+        let shared = &mut self.shared;
+        match door_idx {
+            Some(idx) => self.doors[idx].handle_command(shared, claims, c).await,
+            None => {
+                let door = match self
+                    .doors
+                    .iter()
+                    .find(|door| door.topics.command_topic == topic)
+                {
+                    Some(door) => door,
+                    None => {
+                        error!("message received on unexpected topic {}, ignoring", topic);
+                        return Ok(());
+                    }
+                };
+                warn!(
+                    "command {} rejected: unknown door {:?} for topic {}",
+                    claims.id, claims.door, topic
+                );
+                shared.history.record(HistoryEntry {
+                    id: Some(claims.id.clone()),
+                    door: claims.door.clone(),
+                    ..HistoryEntry::new("unknown_door")
+                });
+                let token = shared.jwt_svc_signing.sign(Claims {
+                    command: "unknown_door".to_owned(),
+                    id: claims.id,
+                    door: claims.door,
+                    ..Claims::default()
+                })?;
+                mqtt::publish(token, door.topics.confirm_topic.clone(), c).await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// processes lock, unlock, status and history commands, reply is sent on confirm topic
+    /// of (first) door listening on the topic
+    async fn handle_controller_command(
+        &mut self,
+        topic: &str,
+        claims: Claims,
+        c: &Client,
+    ) -> Result<()> {
+        info!(
+            "command {} '{}' from '{}'",
+            claims.id, claims.command, claims.iss
+        );
+        self.shared.history.record(HistoryEntry {
+            id: Some(claims.id.clone()),
+            issuer: Some(claims.iss.clone()),
+            command: Some(claims.command.clone()),
+            ..HistoryEntry::new("command")
+        });
+        let door_idx = match self.route(topic, claims.door.as_deref()) {
+            Some(idx) => idx,
+            None => match self
+                .doors
+                .iter()
+                .position(|door| door.topics.command_topic == topic)
+            {
+                Some(idx) => idx,
+                None => {
+                    error!("message received on unexpected topic {}, ignoring", topic);
+                    return Ok(());
+                }
+            },
+        };
+
+        if let Err(reason) = self
+            .shared
+            .policy
+            .evaluate(&claims, &Local::now().naive_local())
+        {
+            warn!("command {} rejected: {}", claims.id, reason);
+            let door = &self.doors[door_idx];
+            return door
+                .reply(&mut self.shared, "policy_denied", claims.id, None, c)
+                .await;
+        }
+
+        match claims.command.as_str() {
+            "lock" => {
+                self.set_locked(true, &claims.iss)?;
+                warn!("controller locked by '{}'", claims.iss);
+            }
+            "unlock" => {
+                if let Err(reason) = self.check_unlock(&claims) {
+                    warn!("command {} rejected: {}", claims.id, reason);
+                    let door = &self.doors[door_idx];
+                    return door
+                        .reply(&mut self.shared, "unlock_denied", claims.id, None, c)
+                        .await;
+                }
+                self.set_locked(false, &claims.iss)?;
+                warn!("controller unlocked by '{}'", claims.iss);
+            }
+            "history" => return self.send_history(door_idx, claims, c).await,
+            _ => return self.publish_status(claims.id, c).await,
+        }
+
+        let door = &self.doors[door_idx];
+        door.reply(&mut self.shared, "confirmation", claims.id.clone(), None, c)
+            .await?;
+        self.publish_status(claims.id, c).await
+    }
+
+    /// replies with requested page of history, optionally only for door given in door claim
+    async fn send_history(&self, door_idx: usize, claims: Claims, c: &Client) -> Result<()> {
+        let page = claims.page.unwrap_or(0);
+        let (entries, pages) =
+            self.shared
+                .history
+                .page(claims.door.as_deref(), page, self.shared.history_page_size);
+        let token = self.shared.jwt_svc_signing.sign(HistoryReply {
+            claims: Claims {
+                command: "history".to_owned(),
+                id: claims.id,
+                door: claims.door,
+                page: Some(page),
+                ..Claims::default()
+            },
+            pages,
+            entries,
+        })?;
+        debug!("history prepared {}", token);
+        let topic = self.doors[door_idx].topics.confirm_topic.clone();
+        mqtt::publish(token, topic, c).await?;
+        Ok(())
+    }
+
+    fn set_locked(&mut self, locked: bool, issuer: &str) -> Result<()> {
+        self.shared.state.update(|state| {
+            state.lock.locked = locked;
+            state.lock.changed_by = Some(issuer.to_owned());
+            state.lock.changed_at = Some(state::now());
+        })
+    }
+
+    /// unlock may require specific issuer and/or second factor flag
+    fn check_unlock(&self, claims: &Claims) -> std::result::Result<(), String> {
+        if let Some(issuer) = &self.shared.unlock_issuer {
+            if issuer != &claims.iss {
+                return Err(format!("unlock not allowed for issuer '{}'", claims.iss));
+            }
+        }
+        if let Some(flag) = &self.shared.unlock_flag {
+            if !claims.flags.contains(flag) {
+                return Err(format!("unlock requires flag '{}'", flag));
+            }
+        }
+        Ok(())
+    }
+
+    /// publishes state of all doors together with lock state on status topics,
+    /// id is id of command which requested the status (or generated one)
+    pub async fn publish_status(&self, id: String, c: &Client) -> Result<()> {
+        for door in &self.doors {
+            door.publish_status(&self.shared, id.clone(), c).await?;
+        }
+        Ok(())
+    }
+
+    /// index of door the command received on given topic is meant for
+    fn route(&self, topic: &str, door_id: Option<&str>) -> Option<usize> {
+        let mut candidates =
+            (0..self.doors.len()).filter(|idx| self.doors[*idx].topics.command_topic == topic);
+        match door_id {
+            Some(door_id) => candidates.find(|idx| self.doors[*idx].id == door_id),
+            // door id can be omitted only if it is unambiguous
+            None => {
+                let first = candidates.next();
+                if candidates.next().is_none() {
+                    first
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// periodic housekeeping, must be called from main loop at least once per second
+    pub async fn tick(&mut self, c: &Client) -> Result<()> {
+        let shared = &mut self.shared;
+        for door in self.doors.iter_mut() {
+            door.tick(shared, c).await?;
+        }
+        Ok(())
+    }
+
+    /// Stops processing of commands. Queued commands get 'shutting_down' reply, movements
+    /// caused by already executed commands are watched until they finish or drain time elapses
+    /// (two-phase commands still waiting for final reply get 'shutting_down' reply). Finally
+    /// signed shutdown event and retained status of every door are published.
+    pub async fn shutdown(&mut self, drain: Duration, c: &Client) -> Result<()> {
+        let shared = &mut self.shared;
+        for door in self.doors.iter_mut() {
+            if let Some(claims) = door.queued_command.take() {
+                info!("queued command {} dropped, shutting down", claims.id);
+                door.reply(shared, SHUTDOWN_REPLY, claims.id, None, c)
+                    .await?;
+            }
+        }
+
+        let started = Instant::now();
+        while self.in_flight() && started.elapsed() < drain {
+            delay_for(DRAIN_POLL_INTERVAL).await;
+            let shared = &mut self.shared;
+            for door in self.doors.iter_mut() {
+                door.record_state(shared)?;
+                door.check_movement(shared, c).await?;
+            }
+        }
+
+        let id = event_id("shutdown");
+        let shared = &mut self.shared;
+        for door in self.doors.iter_mut() {
+            let state = door.gpio.door_state();
+            if let Some(command_id) = door.awaiting_final_reply.take() {
+                warn!("command {} not completed before shutdown", command_id);
+                door.reply(shared, SHUTDOWN_REPLY, command_id, Some(state), c)
+                    .await?;
+            }
+            door.publish_event(shared, "shutdown", id.clone(), Some(state), c)
+                .await?;
+        }
+        self.publish_status(id, c).await
+    }
+
+    /// true if movement caused by some command is still watched
+    pub fn in_flight(&self) -> bool {
+        self.doors
+            .iter()
+            .any(|door| door.movement_monitor.in_progress())
+    }
+
+    /// time until first queued command is due (or wall buttons should be sampled),
+    /// main loop should not wait for new messages longer
+    pub fn next_deadline(&self) -> Option<Duration> {
+        self.doors
+            .iter()
+            .filter_map(DoorHandler::next_deadline)
+            .min()
+    }
+}
+
+impl DoorHandler {
+    fn new(config: &ApplicationConfiguration, door: toml::Door) -> Result<Self> {
+        // relay pin is set to its idle level already during initialization
+        let gpio = gpio::Gpio::new(&door.gpio)?;
+        gpio.relay().install_panic_hook();
+        let button = door
+            .gpio
+            .button_pin
+            .map(|_| Button::new(&door.gpio, Instant::now()));
+
+        Ok(DoorHandler {
+            id: door.id,
+            topics: door.topics,
+            gpio,
+            pulse_duration: Duration::from_millis(door.gpio.pulse_ms),
+            rate_limiter: RateLimiter::new(&config.rate_limit, Instant::now()),
+            queued_command: None,
+            auto_close_timer: AutoCloseTimer::new(&config.auto_close),
+            movement_monitor: MovementMonitor::new(Duration::from_secs(door.gpio.travel_secs)),
+            awaiting_final_reply: None,
+            button,
+            button_command: door.gpio.button_command.clone(),
+            long_press_command: door.gpio.long_press_command.clone(),
+        })
+    }
+
+    async fn handle_command(
+        &mut self,
+        shared: &mut Shared,
+        claims: Claims,
+        c: &Client,
+    ) -> Result<()> {
+        info!(
+            "command {} '{}' from '{}' for door {}",
+            claims.id, claims.command, claims.iss, self.id
+        );
+        shared.history.record(HistoryEntry {
+            id: Some(claims.id.clone()),
+            door: Some(self.id.clone()),
+            issuer: Some(claims.iss.clone()),
+            command: Some(claims.command.clone()),
+            ..HistoryEntry::new("command")
+        });
+        self.execute_command(shared, claims, c).await
+    }
+
+    /// checks and executes command, queued command is checked again once cooldown elapses
+    async fn execute_command(
+        &mut self,
+        shared: &mut Shared,
+        claims: Claims,
+        c: &Client,
+    ) -> Result<()> {
+        // policy is evaluated first so that denied commands do not consume rate limit
+        if let Err(reason) = shared.policy.evaluate(&claims, &Local::now().naive_local()) {
+            warn!("command {} rejected: {}", claims.id, reason);
+            return self
+                .reply(shared, "policy_denied", claims.id, None, c)
+                .await;
+        }
+
+        let target = match claims.command.as_str() {
+            "toggle" => None,
+            "open" => Some(DoorState::Open),
+            "close" => Some(DoorState::Closed),
+            _ => {
+                warn!(
+                    "command {} rejected: unsupported command '{}'",
+                    claims.id, claims.command
+                );
+                return self
+                    .reply(shared, "unsupported_command", claims.id, None, c)
+                    .await;
+            }
+        };
+
+        // while locked, only commands which cannot open the door are accepted
+        if shared.state.state().lock.locked && target != Some(DoorState::Closed) {
+            warn!("command {} rejected: controller is locked", claims.id);
+            return self.reply(shared, "locked", claims.id, None, c).await;
+        }
+
+        // open/close are idempotent, door is pulsed only if it is known to be in opposite position
+        if let Some(target) = target {
+            let state = self.gpio.door_state();
+            match check_target(target, state, shared.unknown_state_policy) {
+                TargetCheck::Pulse => {}
+                TargetCheck::AlreadyThere => {
+                    info!(
+                        "command {} ignored, door {} is already {}",
+                        claims.id,
+                        self.id,
+                        state.as_str()
+                    );
+                    let reply = format!("already_{}", state.as_str());
+                    return self.reply(shared, &reply, claims.id, Some(state), c).await;
+                }
+                TargetCheck::StateUnknown => {
+                    warn!(
+                        "command {} rejected: door {} is {}",
+                        claims.id,
+                        self.id,
+                        state.as_str()
+                    );
+                    return self
+                        .reply(shared, "state_unknown", claims.id, Some(state), c)
+                        .await;
+                }
+            }
+        }
+
+        match self.rate_limiter.check(Instant::now()) {
+            Decision::Allowed => self.toggle(shared, claims, c).await,
+            Decision::CoolingDown(remaining)
+                if shared.queue_during_cooldown && self.queued_command.is_none() =>
+            {
+                info!(
+                    "command {} queued, will be executed in {:?}",
+                    claims.id, remaining
+                );
+                self.queued_command = Some(claims);
+                Ok(())
+            }
+            decision => {
+                warn!("command {} rejected: {:?}", claims.id, decision);
+                self.reply(shared, "rate_limited", claims.id, None, c).await
+            }
+        }
+    }
+
+    async fn tick(&mut self, shared: &mut Shared, c: &Client) -> Result<()> {
+        if let Some(claims) = self.poll_button(Instant::now()) {
+            self.handle_command(shared, claims, c).await?;
+        }
+        self.process_queue(shared, c).await?;
+        self.record_state(shared)?;
+        self.check_movement(shared, c).await?;
+        self.check_auto_close(shared, c).await
+    }
+
+    /// executes queued command if cooldown already elapsed
+    async fn process_queue(&mut self, shared: &mut Shared, c: &Client) -> Result<()> {
+        if self
+            .rate_limiter
+            .remaining_cooldown(Instant::now())
+            .is_some()
+        {
+            return Ok(());
+        }
+        match self.queued_command.take() {
+            Some(claims) => {
+                debug!("executing queued command {}", claims.id);
+                // command was recorded when received already
+                shared.history.record(HistoryEntry {
+                    id: Some(claims.id.clone()),
+                    door: Some(self.id.clone()),
+                    ..HistoryEntry::new("executed")
+                });
+                self.execute_command(shared, claims, c).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// turns wall button press into local command, which is then processed
+    /// the same way as commands from smart home
+    fn poll_button(&mut self, now: Instant) -> Option<Claims> {
+        let press = self
+            .button
+            .as_mut()?
+            .update(self.gpio.button_pressed()?, now)?;
+        let command = match press {
+            Press::Short => self.button_command.clone(),
+            Press::Long => self.long_press_command.clone(),
+        };
+        Some(Claims {
+            iss: BUTTON_ISSUER.to_owned(),
+            command,
+            id: event_id("button"),
+            door: Some(self.id.clone()),
+            ..Claims::default()
+        })
+    }
+
+    fn next_deadline(&self) -> Option<Duration> {
+        let button_deadline = self.button.as_ref().map(|_| BUTTON_POLL_INTERVAL);
+        let queue_deadline = self.queued_command.as_ref().map(|_| {
+            self.rate_limiter
+                .remaining_cooldown(Instant::now())
+                .unwrap_or_else(|| Duration::from_millis(0))
+        });
+        match (button_deadline, queue_deadline) {
+            (Some(button), Some(queue)) => Some(button.min(queue)),
+            (button, queue) => button.or(queue),
+        }
+    }
+
+    /// reports door movement which did not reach expected limit position
+    async fn check_movement(&mut self, shared: &mut Shared, c: &Client) -> Result<()> {
+        let state = self.gpio.door_state();
+        let (id, outcome) = match self.movement_monitor.update(state, Instant::now()) {
+            Some(result) => result,
+            None => return Ok(()),
+        };
+
+        let final_reply = if self.awaiting_final_reply.as_ref() == Some(&id) {
+            self.awaiting_final_reply.take()
+        } else {
+            None
+        };
+
+        if let MovementOutcome::Completed(state) = outcome {
+            debug!(
+                "movement of door {} caused by {} completed, door is {}",
+                self.id,
+                id,
+                state.as_str()
+            );
+            if let Some(id) = final_reply {
+                self.reply(shared, "completed", id, Some(state), c).await?;
+            }
+            return Ok(());
+        }
+
+        warn!(
+            "movement of door {} caused by {} failed: {:?}",
+            self.id, id, outcome
+        );
+        if let Some(id) = final_reply {
+            self.reply(shared, "failed", id, Some(state), c).await?;
+        }
+        self.publish_event(shared, "movement_failed", id, Some(state), c)
+            .await
+    }
+
+    async fn check_auto_close(&mut self, shared: &mut Shared, c: &Client) -> Result<()> {
+        let state = self.gpio.door_state();
+        let action = match self.auto_close_timer.update(state, Instant::now()) {
+            Some(action) => action,
+            None => return Ok(()),
+        };
+
+        warn!("door {} is open for too long", self.id);
+        self.publish_event(
+            shared,
+            "open_too_long",
+            event_id("open_too_long"),
+            Some(state),
+            c,
+        )
+        .await?;
+
+        // sensor must still confirm the door is open (i.e. not moving) right before the pulse
+        if action == AutoCloseAction::AlertAndClose && self.gpio.door_state() == DoorState::Open {
+            let id = event_id("auto_close");
+            // automatic close is subject to the same limits as commands
+            match self.rate_limiter.check(Instant::now()) {
+                Decision::Allowed => {
+                    info!("closing door {} automatically", self.id);
+                    self.pulse(shared, id.clone()).await?;
+                    self.publish_event(shared, "auto_close", id, Some(DoorState::Open), c)
+                        .await?;
+                }
+                decision => {
+                    warn!(
+                        "automatic close of door {} rejected: {:?}",
+                        self.id, decision
+                    );
+                    self.publish_event(
+                        shared,
+                        "auto_close_rate_limited",
+                        id,
+                        Some(DoorState::Open),
+                        c,
+                    )
+                    .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn toggle(&mut self, shared: &mut Shared, claims: Claims, c: &Client) -> Result<()> {
+        // final state can be confirmed only if door movement is tracked by sensors
+        if shared.two_phase_confirmation && self.gpio.has_limit_sensors() {
+            // previous movement is superseded by this command, it will never be completed
+            if let Some(previous_id) = self.awaiting_final_reply.take() {
+                let state = self.gpio.door_state();
+                self.reply(shared, "failed", previous_id, Some(state), c)
+                    .await?;
+            }
+            let state = self.gpio.door_state();
+            self.reply(shared, "accepted", claims.id.clone(), Some(state), c)
+                .await?;
+            self.pulse(shared, claims.id.clone()).await?;
+            // completed/failed reply is sent from tick once movement is finished
+            self.awaiting_final_reply = Some(claims.id);
+            return Ok(());
+        }
+
+        let confirmation_token = self.sign(shared, "confirmation", claims.id.clone(), None)?;
+        debug!("acknowledgment prepared {}", confirmation_token);
+
+        self.pulse(shared, claims.id).await?;
+        debug!("relay released, sending acknowledgment to smart-home");
+
+        mqtt::publish(confirmation_token, self.topics.confirm_topic.clone(), c).await?;
+        debug!("acknowledgment sent!");
+        Ok(())
+    }
+
+    /// pulses the relay and starts watching door movement caused by command with given id
+    async fn pulse(&mut self, shared: &mut Shared, id: String) -> Result<()> {
+        let origin = self.gpio.door_state();
+        // relay is released even if pulse future is dropped
+        self.gpio.relay().pulse(self.pulse_duration).await;
+        self.rate_limiter.pulse_finished(Instant::now());
+
+        let door_id = &self.id;
+        shared.state.update(|state| {
+            let record = state.door(door_id);
+            record.pulses += 1;
+            record.last_pulse_id = Some(id.clone());
+        })?;
+
+        if self.gpio.has_limit_sensors() {
+            self.movement_monitor.start(id, origin, Instant::now());
+        }
+        Ok(())
+    }
+
+    /// keeps last known door state in state store
+    fn record_state(&self, shared: &mut Shared) -> Result<()> {
+        let state = self.gpio.door_state();
+        if state == DoorState::Unknown {
+            return Ok(());
+        }
+        let stored = shared
+            .state
+            .state()
+            .doors
+            .get(&self.id)
+            .and_then(|record| record.last_state.clone());
+        if stored.as_deref() == Some(state.as_str()) {
+            return Ok(());
+        }
+        shared.history.record(HistoryEntry {
+            door: Some(self.id.clone()),
+            state: Some(state.as_str().to_owned()),
+            ..HistoryEntry::new("state")
+        });
+        shared.state.update(|stored| {
+            stored.door(&self.id).last_state = Some(state.as_str().to_owned());
+        })
+    }
+
+    /// door can be operated manually while controller is down (or without power)
+    fn log_offline_change(&self, state: &StateStore) {
+        let last_state = state
+            .state()
+            .doors
+            .get(&self.id)
+            .and_then(|record| record.last_state.as_deref());
+        let current = self.gpio.door_state();
+        if let Some(last_state) = last_state {
+            if current != DoorState::Unknown && last_state != current.as_str() {
+                warn!(
+                    "door {} changed from {} to {} while controller was not running",
+                    self.id,
+                    last_state,
+                    current.as_str()
+                );
+            }
+        }
+    }
+
+    /// publishes signed event on events topic, id is either id of related command or generated one
+    async fn publish_event(
+        &self,
+        shared: &mut Shared,
+        event: &str,
+        id: String,
+        state: Option<DoorState>,
+        c: &Client,
+    ) -> Result<()> {
+        self.record(shared, event, &id, state);
+        let token = self.sign(shared, event, id, state)?;
+        debug!("event prepared {}", token);
+        mqtt::publish(token, self.topics.events_topic.clone(), c).await?;
+        Ok(())
+    }
+
+    async fn publish_status(&self, shared: &Shared, id: String, c: &Client) -> Result<()> {
+        let token = shared.jwt_svc_signing.sign(Claims {
+            command: "status".to_owned(),
+            id,
+            door: Some(self.id.clone()),
+            state: Some(self.gpio.door_state().as_str().to_owned()),
+            locked: Some(shared.state.state().lock.locked),
+            ..Claims::default()
+        })?;
+        debug!("status prepared {}", token);
+        mqtt::publish_retained(token, self.topics.status_topic.clone(), c).await?;
+        Ok(())
+    }
+
+    async fn reply(
+        &self,
+        shared: &mut Shared,
+        command: &str,
+        id: String,
+        state: Option<DoorState>,
+        c: &Client,
+    ) -> Result<()> {
+        self.record(shared, command, &id, state);
+        let token = self.sign(shared, command, id, state)?;
+        debug!("reply prepared {}", token);
+        mqtt::publish(token, self.topics.confirm_topic.clone(), c).await?;
+        Ok(())
+    }
+
+    /// records reply or event sent for this door in history
+    fn record(&self, shared: &mut Shared, event: &str, id: &str, state: Option<DoorState>) {
+        shared.history.record(HistoryEntry {
+            id: Some(id.to_owned()),
+            door: Some(self.id.clone()),
+            state: state.map(|state| state.as_str().to_owned()),
+            ..HistoryEntry::new(event)
+        });
+    }
+
+    fn sign(
+        &self,
+        shared: &Shared,
+        command: &str,
+        id: String,
+        state: Option<DoorState>,
+    ) -> Result<String> {
+        shared.jwt_svc_signing.sign(Claims {
+            command: command.to_owned(),
+            id,
+            door: Some(self.id.clone()),
+            state: state.map(|state| state.as_str().to_owned()),
+            ..Claims::default()
+        })
+    }
+}
+
+/// id for events not triggered by any command
+pub fn event_id(event: &str) -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    format!("{}-{}", event, timestamp)
+}
+
+// tests drive dummy gpio, real pins are not touched
+#[cfg(all(test, not(all(target_family = "unix", target_arch = "arm"))))]
+mod tests {
+    use super::*;
+    use crate::broker::Broker;
+    use crate::errors::Error;
+    use crate::fixtures::{self, AES_KEY, MICROCONTROLLER_KEYS, SMART_HOME_KEYS};
+    use mqtt_async_client::client::{QoS, ReadResult, Subscribe, SubscribeTopic};
+    use proptest::prelude::*;
+    use tokio::time::timeout;
+
+    const CONFIG: &str = r#"
+        [mqtt]
+        host = "localhost"
+        port = 1883
+        username = "user"
+        password = "pass"
+
+        [aes]
+        key = "key"
+
+        [smart_home]
+        pub_key = "smart_home.pem"
+
+        [microcontroller]
+        pub_key = "micro_pub.pem"
+        priv_key = "micro_priv.pem"
+
+        [history]
+        max_entries = 0
+
+        [lock]
+        unlock_issuer = "myhome-cc-smarthome-aog"
+        unlock_flag = "pin_confirmed"
+
+        [[doors]]
+        id = "left"
+        relay_pin = 4
+
+        [[doors]]
+        id = "right"
+        relay_pin = 17
+
+        [[doors]]
+        id = "gate"
+        relay_pin = 27
+        command_topic = "gate/toggle"
+        confirm_topic = "gate/toggleConfirm"
+        events_topic = "gate/events"
+    "#;
+
+    fn controller() -> Result<Controller> {
+        let config = ApplicationConfiguration::from_toml_str(CONFIG)?;
+        Controller::new(&config, JWTService::new("".to_owned(), None))
+    }
+
+    // cargo test -- --show-output test_command_topics
+    #[test]
+    fn test_command_topics() -> Result<()> {
+        let controller = controller()?;
+        assert_eq!(
+            controller.command_topics(),
+            vec![mqtt::TOGGLE_TOPIC.to_owned(), "gate/toggle".to_owned()]
+        );
+        assert!(controller.gpio("gate").is_some());
+        assert!(controller.gpio("garage").is_none());
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_route
+    #[test]
+    fn test_route() -> Result<()> {
+        let controller = controller()?;
+        assert_eq!(controller.route(mqtt::TOGGLE_TOPIC, Some("left")), Some(0));
+        assert_eq!(controller.route(mqtt::TOGGLE_TOPIC, Some("right")), Some(1));
+        // door id is required when more doors share the topic
+        assert_eq!(controller.route(mqtt::TOGGLE_TOPIC, None), None);
+        // door must listen on the topic command was received on
+        assert_eq!(controller.route(mqtt::TOGGLE_TOPIC, Some("gate")), None);
+        assert_eq!(controller.route("gate/toggle", None), Some(2));
+        assert_eq!(controller.route("gate/toggle", Some("gate")), Some(2));
+        assert_eq!(controller.route("gate/toggle", Some("left")), None);
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_check_unlock
+    #[test]
+    fn test_check_unlock() -> Result<()> {
+        let controller = controller()?;
+        let claims = Claims {
+            command: "unlock".to_owned(),
+            ..Claims::default()
+        };
+        assert!(controller.check_unlock(&claims).is_err());
+
+        let claims = Claims {
+            flags: vec!["pin_confirmed".to_owned()],
+            ..claims
+        };
+        assert!(controller.check_unlock(&claims).is_ok());
+
+        let claims = Claims {
+            iss: BUTTON_ISSUER.to_owned(),
+            ..claims
+        };
+        assert!(controller.check_unlock(&claims).is_err());
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_reload
+    #[test]
+    fn test_reload() -> Result<()> {
+        let mut controller = controller()?;
+
+        let invalid = format!(
+            "{}\n[[policy.rules]]\nfrom = \"25:00\"\nto = \"06:00\"\n",
+            CONFIG
+        );
+        let invalid = ApplicationConfiguration::from_toml_str(&invalid)?;
+        assert!(controller
+            .reload(&invalid, JWTService::new("".to_owned(), None))
+            .is_err());
+        assert_eq!(
+            controller.shared.unlock_issuer,
+            Some("myhome-cc-smarthome-aog".to_owned())
+        );
+
+        let config = CONFIG.replace("unlock_issuer = \"myhome-cc-smarthome-aog\"", "");
+        let config = ApplicationConfiguration::from_toml_str(&config)?;
+        controller.reload(&config, JWTService::new("".to_owned(), None))?;
+        assert_eq!(controller.shared.unlock_issuer, None);
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_button_command
+    #[test]
+    fn test_button_command() -> Result<()> {
+        let config = format!("{}\nbutton_pin = 22\n", CONFIG);
+        let config = ApplicationConfiguration::from_toml_str(&config)?;
+        let mut controller = Controller::new(&config, JWTService::new("".to_owned(), None))?;
+        assert_eq!(
+            controller.next_deadline(),
+            Some(BUTTON_POLL_INTERVAL),
+            "button must be sampled frequently"
+        );
+
+        let gate = &mut controller.doors[2];
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        assert!(gate.poll_button(at(0)).is_none());
+
+        gate.gpio.button().unwrap().simulate(true);
+        assert!(gate.poll_button(at(10)).is_none());
+        assert!(gate.poll_button(at(100)).is_none());
+        gate.gpio.button().unwrap().simulate(false);
+        assert!(gate.poll_button(at(200)).is_none());
+        let claims = gate.poll_button(at(300)).unwrap();
+        assert_eq!(claims.command, "toggle");
+        assert_eq!(claims.iss, BUTTON_ISSUER);
+        assert_eq!(claims.door, Some("gate".to_owned()));
+
+        gate.gpio.button().unwrap().simulate(true);
+        assert!(gate.poll_button(at(400)).is_none());
+        let claims = gate.poll_button(at(2500)).unwrap();
+        assert_eq!(claims.command, "close");
+        Ok(())
+    }
+
+    async fn connect(broker: &Broker, topics: Vec<String>) -> Result<Client> {
+        let mut c = mqtt::plain_client(&broker.host(), broker.port(), "user", "pass")?;
+        c.connect().await?;
+        let subopts = Subscribe::new(
+            topics
+                .into_iter()
+                .map(|topic_path| SubscribeTopic {
+                    qos: QoS::AtMostOnce,
+                    topic_path,
+                })
+                .collect(),
+        );
+        c.subscribe(subopts).await?.any_failures()?;
+        Ok(c)
+    }
+
+    async fn read(c: &mut Client) -> Result<ReadResult> {
+        timeout(Duration::from_secs(5), mqtt::read_subscriptions(c))
+            .await
+            .map_err(|_| Error::new("no message received within 5 s".to_owned()))?
+            .map_err(Error::from)
+    }
+
+    // cargo test -- --show-output test_command_flow
+    #[test]
+    fn test_command_flow() -> Result<()> {
+        let mut rt = tokio::runtime::Runtime::new()?;
+        rt.block_on(async {
+            let broker = Broker::start().await?;
+            let config = CONFIG.replace("key = \"key\"", &format!("key = \"{}\"", *AES_KEY));
+            let config = ApplicationConfiguration::from_toml_str(&config)?;
+            let mut controller = Controller::new(&config, MICROCONTROLLER_KEYS.signing())?;
+            let mut c = connect(&broker, controller.command_topics()).await?;
+            let mut smart_home =
+                connect(&broker, vec![mqtt::TOGGLE_CONFIRM_TOPIC.to_owned()]).await?;
+
+            // smart home publishes encrypted and signed command
+            let id = event_id("test-command-flow");
+            let payload = fixtures::encrypted_command(Claims {
+                door: Some("left".to_owned()),
+                ..fixtures::command("toggle", &id)
+            })?;
+            mqtt::publish(payload, mqtt::TOGGLE_TOPIC.to_owned(), &smart_home).await?;
+
+            // controller receives it, pulses the relay and confirms
+            let r = read(&mut c).await?;
+            assert_eq!(r.topic(), mqtt::TOGGLE_TOPIC);
+            controller
+                .handle_message(
+                    r.topic(),
+                    r.payload(),
+                    &AES_KEY,
+                    &SMART_HOME_KEYS.verif(),
+                    &c,
+                )
+                .await?;
+            let record = &controller.shared.state.state().doors["left"];
+            assert_eq!(record.last_pulse_id, Some(id.clone()));
+            assert!(!controller.gpio("left").unwrap().relay().is_active());
+
+            let r = read(&mut smart_home).await?;
+            assert_eq!(r.topic(), mqtt::TOGGLE_CONFIRM_TOPIC);
+            let reply = MICROCONTROLLER_KEYS
+                .verif()
+                .verify(&String::from_utf8(r.payload().to_vec())?, true)?;
+            assert_eq!(reply.command, "confirmation");
+            assert_eq!(reply.id, id);
+            assert_eq!(reply.door, Some("left".to_owned()));
+
+            // message which cannot be decrypted is ignored
+            controller
+                .handle_message(
+                    mqtt::TOGGLE_TOPIC,
+                    b"garbage",
+                    &AES_KEY,
+                    &SMART_HOME_KEYS.verif(),
+                    &c,
+                )
+                .await?;
+            Ok(())
+        })
+    }
+
+    // cargo test -- --show-output test_invalid_message_ignored
+    #[test]
+    fn test_invalid_message_ignored() -> Result<()> {
+        let mut rt = tokio::runtime::Runtime::new()?;
+        rt.block_on(async {
+            let broker = Broker::start().await?;
+            let history_file =
+                std::env::temp_dir().join(format!("garage-invalid-{}.log", std::process::id()));
+            let config = CONFIG
+                .replace("key = \"key\"", &format!("key = \"{}\"", *AES_KEY))
+                .replace(
+                    "max_entries = 0",
+                    &format!("max_entries = 10\nfile = {:?}", history_file),
+                );
+            let config = ApplicationConfiguration::from_toml_str(&config)?;
+            let mut controller = Controller::new(&config, MICROCONTROLLER_KEYS.signing())?;
+            let mut c = connect(&broker, controller.command_topics()).await?;
+            let mut smart_home =
+                connect(&broker, vec![mqtt::TOGGLE_CONFIRM_TOPIC.to_owned()]).await?;
+
+            // anybody can publish garbage on command topic before valid command
+            let id = event_id("test-invalid-message");
+            let command = fixtures::encrypted_command(Claims {
+                door: Some("left".to_owned()),
+                ..fixtures::command("toggle", &id)
+            })?;
+            for payload in &["garbage".to_owned(), command] {
+                mqtt::publish(payload.clone(), mqtt::TOGGLE_TOPIC.to_owned(), &smart_home).await?;
+            }
+            for _ in 0..2 {
+                let r = read(&mut c).await?;
+                controller
+                    .handle_message(
+                        r.topic(),
+                        r.payload(),
+                        &AES_KEY,
+                        &SMART_HOME_KEYS.verif(),
+                        &c,
+                    )
+                    .await?;
+            }
+
+            let r = read(&mut smart_home).await?;
+            let reply = MICROCONTROLLER_KEYS
+                .verif()
+                .verify(&String::from_utf8(r.payload().to_vec())?, true)?;
+            assert_eq!(reply.command, "confirmation");
+            assert_eq!(reply.id, id);
+
+            let (entries, _) = controller.shared.history.page(None, 0, 10);
+            assert!(entries.iter().any(|entry| entry.event == "invalid_message"));
+            std::fs::remove_file(&history_file)?;
+            Ok(())
+        })
+    }
+
+    // cargo test -- --show-output test_queued_command_recorded_once
+    #[test]
+    fn test_queued_command_recorded_once() -> Result<()> {
+        let mut rt = tokio::runtime::Runtime::new()?;
+        rt.block_on(async {
+            let broker = Broker::start().await?;
+            let history_file =
+                std::env::temp_dir().join(format!("garage-queued-{}.log", std::process::id()));
+            let config = CONFIG
+                .replace(
+                    "[history]",
+                    "[rate_limit]\ncooldown_ms = 100\nqueue_during_cooldown = true\n\n[history]",
+                )
+                .replace(
+                    "max_entries = 0",
+                    &format!("max_entries = 20\nfile = {:?}", history_file),
+                );
+            let config = ApplicationConfiguration::from_toml_str(&config)?;
+            let mut controller = Controller::new(&config, MICROCONTROLLER_KEYS.signing())?;
+            let c = connect(&broker, vec![]).await?;
+
+            // command received during cooldown is executed once cooldown elapses
+            controller.doors[0]
+                .rate_limiter
+                .pulse_finished(Instant::now());
+            let id = event_id("test-queued");
+            let claims = Claims {
+                door: Some("left".to_owned()),
+                ..fixtures::command("toggle", &id)
+            };
+            controller
+                .handle_command(mqtt::TOGGLE_TOPIC, claims, &c)
+                .await?;
+            assert!(controller.doors[0].queued_command.is_some());
+            delay_for(Duration::from_millis(150)).await;
+            controller.tick(&c).await?;
+            assert!(controller.doors[0].queued_command.is_none());
+
+            let (entries, _) = controller.shared.history.page(None, 0, 20);
+            let events: Vec<&str> = entries
+                .iter()
+                .rev()
+                .filter(|entry| entry.id.as_deref() == Some(id.as_str()))
+                .map(|entry| entry.event.as_str())
+                .collect();
+            assert_eq!(events, vec!["command", "executed"]);
+            std::fs::remove_file(&history_file)?;
+            Ok(())
+        })
+    }
+
+    // cargo test -- --show-output test_auto_close_rate_limited
+    #[test]
+    fn test_auto_close_rate_limited() -> Result<()> {
+        let mut rt = tokio::runtime::Runtime::new()?;
+        rt.block_on(async {
+            let broker = Broker::start().await?;
+            let config = CONFIG
+                .replace("relay_pin = 17", "relay_pin = 17\nopen_sensor_pin = 22")
+                .replace(
+                    "[history]",
+                    "[auto_close]\nalert_after_secs = 0\nclose = true\n\n[history]",
+                );
+            let config = ApplicationConfiguration::from_toml_str(&config)?;
+            let mut controller = Controller::new(&config, MICROCONTROLLER_KEYS.signing())?;
+            let c = connect(&broker, vec![]).await?;
+            let mut smart_home = connect(&broker, vec![mqtt::EVENTS_TOPIC.to_owned()]).await?;
+
+            // door was pulsed just now, cooldown is running
+            let right = &mut controller.doors[1];
+            right.gpio.open_sensor().unwrap().simulate(true);
+            right.rate_limiter.pulse_finished(Instant::now());
+            controller.tick(&c).await?;
+
+            let mut events = vec![];
+            while events.len() < 2 {
+                let r = read(&mut smart_home).await?;
+                let claims = MICROCONTROLLER_KEYS
+                    .verif()
+                    .verify(&String::from_utf8(r.payload().to_vec())?, true)?;
+                events.push(claims.command);
+            }
+            assert_eq!(events, vec!["open_too_long", "auto_close_rate_limited"]);
+            assert!(controller.doors[1]
+                .rate_limiter
+                .remaining_cooldown(Instant::now())
+                .is_some());
+            let state = controller.shared.state.state();
+            assert_eq!(state.doors.get("right").map_or(0, |door| door.pulses), 0);
+            Ok(())
+        })
+    }
+
+    // cargo test -- --show-output test_shutdown
+    #[test]
+    fn test_shutdown() -> Result<()> {
+        let mut rt = tokio::runtime::Runtime::new()?;
+        rt.block_on(async {
+            let broker = Broker::start().await?;
+            let config = CONFIG.replace(
+                "relay_pin = 17",
+                "relay_pin = 17\nopen_sensor_pin = 22\nclosed_sensor_pin = 23",
+            );
+            let config = ApplicationConfiguration::from_toml_str(&config)?;
+            let mut controller = Controller::new(&config, MICROCONTROLLER_KEYS.signing())?;
+            let c = connect(&broker, vec![]).await?;
+            let mut smart_home = connect(&broker, vec!["#".to_owned()]).await?;
+
+            // queued command is not executed, two-phase command on door without sensors is
+            // never completed, moving door reaches its limit position while draining
+            controller.doors[2].queued_command = Some(Claims {
+                command: "toggle".to_owned(),
+                id: "queued".to_owned(),
+                ..Claims::default()
+            });
+            controller.doors[0].awaiting_final_reply = Some("unconfirmed".to_owned());
+            let right = &mut controller.doors[1];
+            let open = right.gpio.open_sensor().unwrap().clone();
+            let closed = right.gpio.closed_sensor().unwrap().clone();
+            closed.simulate(true);
+            right
+                .movement_monitor
+                .start("moving".to_owned(), DoorState::Closed, Instant::now());
+            right.awaiting_final_reply = Some("moving".to_owned());
+            closed.simulate(false);
+            assert!(controller.in_flight());
+            tokio::spawn(async move {
+                delay_for(Duration::from_millis(200)).await;
+                open.simulate(true);
+            });
+
+            controller.shutdown(Duration::from_secs(5), &c).await?;
+            assert!(!controller.in_flight());
+
+            let mut messages = vec![];
+            while messages.len() < 9 {
+                let r = read(&mut smart_home).await?;
+                let claims = MICROCONTROLLER_KEYS
+                    .verif()
+                    .verify(&String::from_utf8(r.payload().to_vec())?, true)?;
+                messages.push((r.topic().to_owned(), claims));
+            }
+            let find = |id: &str| messages.iter().find(|(_, claims)| claims.id == id).unwrap();
+            let (topic, reply) = find("queued");
+            assert_eq!(topic, "gate/toggleConfirm");
+            assert_eq!(reply.command, SHUTDOWN_REPLY);
+            let (_, reply) = find("unconfirmed");
+            assert_eq!(reply.command, SHUTDOWN_REPLY);
+            let (_, reply) = find("moving");
+            assert_eq!(reply.command, "completed");
+            assert_eq!(reply.state, Some("open".to_owned()));
+
+            let count = |command: &str| {
+                messages
+                    .iter()
+                    .filter(|(_, claims)| claims.command == command)
+                    .count()
+            };
+            assert_eq!(count("shutdown"), 3);
+            assert_eq!(count("status"), 3);
+            let status = broker.retained(mqtt::STATUS_TOPIC).unwrap();
+            let status = MICROCONTROLLER_KEYS
+                .verif()
+                .verify(&String::from_utf8(status.payload)?, true)?;
+            assert!(status.id.starts_with("shutdown-"));
+            Ok(())
+        })
+    }
+
+    // cargo test -- --show-output test_decode_command
+    #[test]
+    fn test_decode_command() -> Result<()> {
+        let verif = SMART_HOME_KEYS.verif();
+        let payload = fixtures::encrypted_command(fixtures::command("toggle", "7"))?;
+        let claims = decode_command(payload.as_bytes(), &AES_KEY, &verif)?;
+        assert_eq!(claims.id, "7");
+
+        assert!(decode_command(&[0xff, 0xfe], &AES_KEY, &verif).is_err());
+        let other_key = crate::provision::generate_aes_key();
+        assert!(decode_command(payload.as_bytes(), &other_key, &verif).is_err());
+        // signed but not encrypted
+        let token = fixtures::signed_command(fixtures::command("toggle", "8"))?;
+        assert!(decode_command(token.as_bytes(), &AES_KEY, &verif).is_err());
+        Ok(())
+    }
+
+    proptest! {
+        // cargo test -- --show-output test_decode_arbitrary_payload
+        #[test]
+        fn test_decode_arbitrary_payload(payload in any::<Vec<u8>>()) {
+            prop_assert!(decode_command(&payload, &AES_KEY, &SMART_HOME_KEYS.verif()).is_err());
+        }
+
+        // cargo test -- --show-output test_decode_encrypted_garbage
+        #[test]
+        fn test_decode_encrypted_garbage(text in any::<String>()) {
+            let payload = aes::encrypt(&text, &AES_KEY).unwrap();
+            prop_assert!(decode_command(payload.as_bytes(), &AES_KEY, &SMART_HOME_KEYS.verif()).is_err());
+        }
+
+        // cargo test -- --show-output test_decode_corrupted_command
+        #[test]
+        fn test_decode_corrupted_command(idx in any::<prop::sample::Index>(), byte in any::<u8>()) {
+            let mut payload = fixtures::encrypted_command(fixtures::command("toggle", "9")).unwrap().into_bytes();
+            let idx = idx.index(payload.len());
+            payload[idx] = byte;
+            // tampered command is either rejected or decodes to the very same claims
+            if let Ok(decoded) = decode_command(&payload, &AES_KEY, &SMART_HOME_KEYS.verif()) {
+                prop_assert_eq!(decoded.id, "9");
+                prop_assert_eq!(decoded.command, "toggle");
+            }
+        }
+    }
+}
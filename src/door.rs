@@ -0,0 +1,383 @@
+use crate::toml::{AutoClose, UnknownStatePolicy};
+use std::time::{Duration, Instant};
+
+/// door position as reported by limit sensors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DoorState {
+    /// fully open limit sensor is active
+    Open,
+    /// fully closed limit sensor is active
+    Closed,
+    /// both sensors are configured and none of them is active
+    Moving,
+    /// state cannot be determined from configured sensors
+    Unknown,
+}
+
+impl DoorState {
+    /// derives door state from limit sensors, None means sensor is not configured
+    pub fn from_sensors(open_limit: Option<bool>, closed_limit: Option<bool>) -> Self {
+        match (open_limit, closed_limit) {
+            // both limits at once means faulty wiring
+            (Some(true), Some(true)) => DoorState::Unknown,
+            (Some(true), _) => DoorState::Open,
+            (_, Some(true)) => DoorState::Closed,
+            (Some(false), Some(false)) => DoorState::Moving,
+            _ => DoorState::Unknown,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DoorState::Open => "open",
+            DoorState::Closed => "closed",
+            DoorState::Moving => "moving",
+            DoorState::Unknown => "unknown",
+        }
+    }
+
+    fn is_limit(self) -> bool {
+        self == DoorState::Open || self == DoorState::Closed
+    }
+}
+
+/// result of checking 'open'/'close' command against current door state
+#[derive(Debug, PartialEq)]
+pub enum TargetCheck {
+    /// door is known to be in opposite position (or policy allows pulsing blindly)
+    Pulse,
+    /// door is already in target position, no actuation needed
+    AlreadyThere,
+    /// door state is unknown (or door is moving) and policy does not allow pulsing
+    StateUnknown,
+}
+
+pub fn check_target(
+    target: DoorState,
+    state: DoorState,
+    unknown_state_policy: UnknownStatePolicy,
+) -> TargetCheck {
+    if state == target {
+        TargetCheck::AlreadyThere
+    } else if state.is_limit() || unknown_state_policy == UnknownStatePolicy::Pulse {
+        TargetCheck::Pulse
+    } else {
+        TargetCheck::StateUnknown
+    }
+}
+
+/// result of door movement triggered by pulse
+#[derive(Debug, PartialEq)]
+pub enum MovementOutcome {
+    /// door reached expected limit position
+    Completed(DoorState),
+    /// door returned to original position (e.g. obstruction detected by door engine)
+    Reversed,
+    /// expected limit position was not reached within travel time, contains last known state
+    TimedOut(DoorState),
+}
+
+struct Movement {
+    id: String,
+    origin: DoorState,
+    /// None if any limit position is acceptable (door was moving or state was unknown)
+    target: Option<DoorState>,
+    left_origin: bool,
+    deadline: Instant,
+}
+
+/// Watches door after each pulse, expecting the opposite limit sensor
+/// to become active within configured travel time.
+pub struct MovementMonitor {
+    travel_time: Duration,
+    movement: Option<Movement>,
+}
+
+impl MovementMonitor {
+    pub fn new(travel_time: Duration) -> Self {
+        MovementMonitor {
+            travel_time,
+            movement: None,
+        }
+    }
+
+    /// starts watching door movement caused by command with given id,
+    /// origin is door state right before the pulse
+    pub fn start(&mut self, id: String, origin: DoorState, now: Instant) {
+        let target = match origin {
+            DoorState::Open => Some(DoorState::Closed),
+            DoorState::Closed => Some(DoorState::Open),
+            _ => None,
+        };
+        self.movement = Some(Movement {
+            id,
+            origin,
+            target,
+            left_origin: false,
+            deadline: now + self.travel_time,
+        });
+    }
+
+    /// returns id of command which caused the movement together
+    /// with the outcome, once the movement is finished
+    pub fn update(&mut self, state: DoorState, now: Instant) -> Option<(String, MovementOutcome)> {
+        let movement = self.movement.as_mut()?;
+
+        let outcome = match movement.target {
+            Some(target) if state == target => Some(MovementOutcome::Completed(state)),
+            None if state.is_limit() => Some(MovementOutcome::Completed(state)),
+            _ if movement.left_origin && state == movement.origin => {
+                Some(MovementOutcome::Reversed)
+            }
+            _ if now >= movement.deadline => Some(MovementOutcome::TimedOut(state)),
+            _ => None,
+        };
+        if state != movement.origin {
+            movement.left_origin = true;
+        }
+
+        let outcome = outcome?;
+        let movement = self.movement.take()?;
+        Some((movement.id, outcome))
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.movement.is_some()
+    }
+}
+
+/// action requested by auto close timer
+#[derive(Debug, PartialEq)]
+pub enum AutoCloseAction {
+    /// door is open for too long, warning should be published
+    Alert,
+    /// warning should be published and door closed
+    AlertAndClose,
+}
+
+/// Tracks how long the door is open. Alert (and optional close) is triggered once
+/// per opening, i.e. timer is re-armed only after door leaves open position.
+pub struct AutoCloseTimer {
+    alert_after: Option<Duration>,
+    close: bool,
+    open_since: Option<Instant>,
+    triggered: bool,
+}
+
+impl AutoCloseTimer {
+    pub fn new(config: &AutoClose) -> Self {
+        AutoCloseTimer {
+            alert_after: config.alert_after_secs.map(Duration::from_secs),
+            close: config.close,
+            open_since: None,
+            triggered: false,
+        }
+    }
+
+    pub fn update(&mut self, state: DoorState, now: Instant) -> Option<AutoCloseAction> {
+        let alert_after = self.alert_after?;
+
+        if state != DoorState::Open {
+            self.open_since = None;
+            self.triggered = false;
+            return None;
+        }
+
+        let open_since = *self.open_since.get_or_insert(now);
+        if self.triggered || now.saturating_duration_since(open_since) < alert_after {
+            return None;
+        }
+
+        self.triggered = true;
+        if self.close {
+            Some(AutoCloseAction::AlertAndClose)
+        } else {
+            Some(AutoCloseAction::Alert)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timer(close: bool) -> AutoCloseTimer {
+        AutoCloseTimer::new(&AutoClose {
+            alert_after_secs: Some(600),
+            close,
+        })
+    }
+
+    // cargo test -- --show-output test_from_sensors
+    #[test]
+    fn test_from_sensors() {
+        assert_eq!(DoorState::from_sensors(None, None), DoorState::Unknown);
+        assert_eq!(
+            DoorState::from_sensors(Some(false), None),
+            DoorState::Unknown
+        );
+        assert_eq!(DoorState::from_sensors(Some(true), None), DoorState::Open);
+        assert_eq!(DoorState::from_sensors(None, Some(true)), DoorState::Closed);
+        assert_eq!(
+            DoorState::from_sensors(Some(false), Some(false)),
+            DoorState::Moving
+        );
+        assert_eq!(
+            DoorState::from_sensors(Some(true), Some(true)),
+            DoorState::Unknown
+        );
+    }
+
+    // cargo test -- --show-output test_check_target
+    #[test]
+    fn test_check_target() {
+        use DoorState::*;
+        use UnknownStatePolicy::*;
+
+        assert_eq!(check_target(Open, Open, Reject), TargetCheck::AlreadyThere);
+        assert_eq!(
+            check_target(Closed, Closed, Pulse),
+            TargetCheck::AlreadyThere
+        );
+        assert_eq!(check_target(Open, Closed, Reject), TargetCheck::Pulse);
+        assert_eq!(check_target(Closed, Open, Reject), TargetCheck::Pulse);
+        assert_eq!(
+            check_target(Open, Unknown, Reject),
+            TargetCheck::StateUnknown
+        );
+        assert_eq!(
+            check_target(Closed, Moving, Reject),
+            TargetCheck::StateUnknown
+        );
+        assert_eq!(check_target(Open, Unknown, Pulse), TargetCheck::Pulse);
+        assert_eq!(check_target(Closed, Moving, Pulse), TargetCheck::Pulse);
+    }
+
+    // cargo test -- --show-output test_movement_completed
+    #[test]
+    fn test_movement_completed() {
+        let start = Instant::now();
+        let mut monitor = MovementMonitor::new(Duration::from_secs(30));
+        monitor.start("123".to_owned(), DoorState::Open, start);
+
+        assert_eq!(monitor.update(DoorState::Open, start), None);
+        assert_eq!(
+            monitor.update(DoorState::Moving, start + Duration::from_secs(5)),
+            None
+        );
+        assert_eq!(
+            monitor.update(DoorState::Closed, start + Duration::from_secs(20)),
+            Some((
+                "123".to_owned(),
+                MovementOutcome::Completed(DoorState::Closed)
+            ))
+        );
+        assert!(!monitor.in_progress());
+    }
+
+    // cargo test -- --show-output test_movement_reversed
+    #[test]
+    fn test_movement_reversed() {
+        let start = Instant::now();
+        let mut monitor = MovementMonitor::new(Duration::from_secs(30));
+        monitor.start("123".to_owned(), DoorState::Open, start);
+
+        assert_eq!(
+            monitor.update(DoorState::Moving, start + Duration::from_secs(5)),
+            None
+        );
+        assert_eq!(
+            monitor.update(DoorState::Open, start + Duration::from_secs(10)),
+            Some(("123".to_owned(), MovementOutcome::Reversed))
+        );
+    }
+
+    // cargo test -- --show-output test_movement_timed_out
+    #[test]
+    fn test_movement_timed_out() {
+        let start = Instant::now();
+        let mut monitor = MovementMonitor::new(Duration::from_secs(30));
+        monitor.start("123".to_owned(), DoorState::Closed, start);
+
+        assert_eq!(
+            monitor.update(DoorState::Moving, start + Duration::from_secs(29)),
+            None
+        );
+        assert_eq!(
+            monitor.update(DoorState::Moving, start + Duration::from_secs(30)),
+            Some((
+                "123".to_owned(),
+                MovementOutcome::TimedOut(DoorState::Moving)
+            ))
+        );
+    }
+
+    // cargo test -- --show-output test_movement_from_unknown_state
+    #[test]
+    fn test_movement_from_unknown_state() {
+        let start = Instant::now();
+        let mut monitor = MovementMonitor::new(Duration::from_secs(30));
+        monitor.start("123".to_owned(), DoorState::Moving, start);
+
+        assert_eq!(
+            monitor.update(DoorState::Open, start + Duration::from_secs(10)),
+            Some((
+                "123".to_owned(),
+                MovementOutcome::Completed(DoorState::Open)
+            ))
+        );
+    }
+
+    // cargo test -- --show-output test_alert_once_per_opening
+    #[test]
+    fn test_alert_once_per_opening() {
+        let start = Instant::now();
+        let mut timer = timer(false);
+
+        assert_eq!(timer.update(DoorState::Open, start), None);
+        let later = start + Duration::from_secs(599);
+        assert_eq!(timer.update(DoorState::Open, later), None);
+        let later = start + Duration::from_secs(600);
+        assert_eq!(
+            timer.update(DoorState::Open, later),
+            Some(AutoCloseAction::Alert)
+        );
+        let later = start + Duration::from_secs(1200);
+        assert_eq!(timer.update(DoorState::Open, later), None);
+
+        // door left open position, timer is re-armed
+        assert_eq!(timer.update(DoorState::Moving, later), None);
+        assert_eq!(timer.update(DoorState::Open, later), None);
+        let later = later + Duration::from_secs(600);
+        assert_eq!(
+            timer.update(DoorState::Open, later),
+            Some(AutoCloseAction::Alert)
+        );
+    }
+
+    // cargo test -- --show-output test_alert_and_close
+    #[test]
+    fn test_alert_and_close() {
+        let start = Instant::now();
+        let mut timer = timer(true);
+
+        assert_eq!(timer.update(DoorState::Open, start), None);
+        assert_eq!(
+            timer.update(DoorState::Open, start + Duration::from_secs(700)),
+            Some(AutoCloseAction::AlertAndClose)
+        );
+    }
+
+    // cargo test -- --show-output test_disabled
+    #[test]
+    fn test_disabled() {
+        let start = Instant::now();
+        let mut timer = AutoCloseTimer::new(&AutoClose::default());
+
+        assert_eq!(timer.update(DoorState::Open, start), None);
+        assert_eq!(
+            timer.update(DoorState::Open, start + Duration::from_secs(100_000)),
+            None
+        );
+    }
+}
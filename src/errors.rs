@@ -18,6 +18,12 @@ impl Error {
 
 pub type Result<T> = result::Result<T, Error>;
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
 impl From<jsonwebtoken::errors::Error> for Error {
     fn from(error: jsonwebtoken::errors::Error) -> Error {
         Error {
@@ -66,6 +72,14 @@ impl From<toml::de::Error> for Error {
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Error {
+        Error {
+            message: format!("serde_json::Error: {}", error),
+        }
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(error: std::io::Error) -> Error {
         Error {
@@ -82,6 +96,14 @@ impl From<mqtt_async_client::Error> for Error {
     }
 }
 
+impl From<mqttrs::Error> for Error {
+    fn from(error: mqttrs::Error) -> Error {
+        Error {
+            message: format!("mqttrs::Error: {}", error),
+        }
+    }
+}
+
 #[cfg(all(target_family = "unix", target_arch = "arm"))]
 impl From<rppal::gpio::Error> for Error {
     fn from(error: rppal::gpio::Error) -> Error {
@@ -0,0 +1,78 @@
+use crate::aes;
+use crate::errors::Result;
+use crate::jwt::{Claims, JWTService};
+use crate::provision::{generate_aes_key, generate_key_pair};
+use lazy_static::lazy_static;
+
+/// PEM encoded key pair generated for test run
+pub struct KeyPair {
+    pub private_key: String,
+    pub public_key: String,
+}
+
+impl KeyPair {
+    fn generate() -> KeyPair {
+        let (private_key, public_key) = generate_key_pair(2048).unwrap();
+        KeyPair {
+            private_key,
+            public_key,
+        }
+    }
+
+    /// service signing by private key and verifying by public key
+    pub fn signing(&self) -> JWTService {
+        JWTService::new(self.public_key.clone(), Some(self.private_key.clone()))
+    }
+
+    /// service verifying by public key only
+    pub fn verif(&self) -> JWTService {
+        JWTService::new(self.public_key.clone(), None)
+    }
+}
+
+// key generation is slow, keys are generated once and shared by all tests
+lazy_static! {
+    /// keys of smart home fulfillment signing commands
+    pub static ref SMART_HOME_KEYS: KeyPair = KeyPair::generate();
+    /// keys of microcontroller signing replies, events and status
+    pub static ref MICROCONTROLLER_KEYS: KeyPair = KeyPair::generate();
+    /// aes key shared by smart home and microcontroller
+    pub static ref AES_KEY: String = generate_aes_key();
+}
+
+/// command claims with default (one minute) expiry
+pub fn command(command: &str, id: &str) -> Claims {
+    Claims {
+        command: command.to_owned(),
+        id: id.to_owned(),
+        ..Claims::default()
+    }
+}
+
+/// token signed by smart home, as verified by microcontroller
+pub fn signed_command(claims: Claims) -> Result<String> {
+    SMART_HOME_KEYS.signing().sign(claims)
+}
+
+/// payload published by smart home on command topic
+pub fn encrypted_command(claims: Claims) -> Result<String> {
+    aes::encrypt(&signed_command(claims)?, &AES_KEY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // cargo test -- --show-output test_encrypted_command
+    #[test]
+    fn test_encrypted_command() -> Result<()> {
+        aes::check_key(&AES_KEY)?;
+        let payload = encrypted_command(command("toggle", "1"))?;
+        let token = aes::decrypt(&payload, &AES_KEY)?;
+        let claims = SMART_HOME_KEYS.verif().verify(&token, true)?;
+        assert_eq!(claims.command, "toggle");
+        assert_eq!(claims.id, "1");
+        assert!(MICROCONTROLLER_KEYS.verif().verify(&token, true).is_err());
+        Ok(())
+    }
+}
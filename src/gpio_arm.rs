@@ -1,40 +1,150 @@
+use crate::door::DoorState;
 use crate::errors::Result;
+use crate::relay::{Relay, RelayPin};
+use crate::toml::GPIO;
 use log::debug;
 use rppal;
+use rppal::gpio::{Level, Mode};
+use std::time::Duration;
 
 // see https://www.raspberrypi-spy.co.uk/2012/06/simple-guide-to-the-rpi-gpio-header-and-pins/
-// pin 7 / GPIO04, relay power connected to pin 1 (3V3), relay ground to pin 6
+// default setup: pin 7 / GPIO04, relay power connected to pin 1 (3V3), relay ground to pin 6
 // rppal uses GPIO.BCM, not GPIO.BOARD numbering
-const OUTPUT_PINT: u8 = 4;
-
 pub struct Gpio {
     #[allow(dead_code)]
     gpio_handler: rppal::gpio::Gpio,
-    pin: rppal::gpio::OutputPin,
+    relay: Relay<OutputPin>,
+    open_sensor: Option<InputPin>,
+    closed_sensor: Option<InputPin>,
+    button: Option<InputPin>,
+}
+
+/// relay output pin with configured polarity
+pub struct OutputPin {
+    pin: rppal::gpio::IoPin,
+    active_low: bool,
+}
+
+impl RelayPin for OutputPin {
+    fn set_active(&mut self, active: bool) {
+        if active {
+            self.pin.write(active_level(self.active_low));
+        } else {
+            self.pin.write(idle_level(self.active_low));
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.pin.read() == active_level(self.active_low)
+    }
+}
+
+/// sensor or button input pin with configured polarity
+pub struct InputPin {
+    pin: rppal::gpio::InputPin,
+    active_low: bool,
+}
+
+impl InputPin {
+    fn new(handler: &rppal::gpio::Gpio, pin: u8, active_low: bool) -> Result<Self> {
+        let pin = handler.get(pin)?;
+        // sensor (e.g. reed switch) or button connects pin either to ground or to 3V3,
+        // internal resistor keeps the pin at idle level otherwise
+        let pin = if active_low {
+            pin.into_input_pullup()
+        } else {
+            pin.into_input_pulldown()
+        };
+        Ok(InputPin { pin, active_low })
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.pin.read() == active_level(self.active_low)
+    }
 }
 
 impl Gpio {
-    pub fn new() -> Result<Self> {
-        debug!("initiating on arm architecture, creating real Gpio handler");
+    pub fn new(config: &GPIO) -> Result<Self> {
+        debug!(
+            "initiating on arm architecture, creating real Gpio handler for pin {} (active_low: {})",
+            config.relay_pin, config.active_low
+        );
         let handler = rppal::gpio::Gpio::new()?;
-        let output_pin = handler.get(OUTPUT_PINT)?.into_output();
+
+        // write idle level into output latch while pin is still input
+        // so that relay does not click when pin is switched to output
+        let mut pin = handler.get(config.relay_pin)?.into_io(Mode::Input);
+        pin.write(idle_level(config.active_low));
+        pin.set_mode(Mode::Output);
+
+        let output_pin = OutputPin {
+            pin,
+            active_low: config.active_low,
+        };
+
+        let open_sensor = match config.open_sensor_pin {
+            Some(pin) => Some(InputPin::new(&handler, pin, config.sensor_active_low)?),
+            None => None,
+        };
+        let closed_sensor = match config.closed_sensor_pin {
+            Some(pin) => Some(InputPin::new(&handler, pin, config.sensor_active_low)?),
+            None => None,
+        };
+        let button = match config.button_pin {
+            Some(pin) => Some(InputPin::new(&handler, pin, config.button_active_low)?),
+            None => None,
+        };
+
         Ok(Gpio {
             gpio_handler: handler,
-            pin: output_pin,
+            relay: Relay::new(output_pin, Duration::from_millis(config.max_on_ms)),
+            open_sensor,
+            closed_sensor,
+            button,
         })
     }
 
-    pub fn set_pin_high(&mut self) {
-        debug!("Setting pin HIGH");
-        // based on test with real raspberry 3b
-        // we need to call set_low to set the pin actually high
-        self.pin.set_low();
+    pub fn relay(&self) -> &Relay<OutputPin> {
+        &self.relay
+    }
+
+    pub fn door_state(&self) -> DoorState {
+        let (open_limit, closed_limit) = self.sensors();
+        DoorState::from_sensors(open_limit, closed_limit)
+    }
+
+    /// levels of fully open and fully closed limit sensors, None if sensor is not configured
+    pub fn sensors(&self) -> (Option<bool>, Option<bool>) {
+        (
+            self.open_sensor.as_ref().map(InputPin::is_active),
+            self.closed_sensor.as_ref().map(InputPin::is_active),
+        )
     }
 
-    pub fn set_pin_low(&mut self) {
-        debug!("Setting pin LOW");
-        // based on test with real raspberry 3b
-        // we need to call set_high to set the pin actually low
-        self.pin.set_high();
+    /// true if both fully open and fully closed limit sensors are configured,
+    /// i.e. door movement can be tracked
+    pub fn has_limit_sensors(&self) -> bool {
+        self.open_sensor.is_some() && self.closed_sensor.is_some()
+    }
+
+    /// raw (not debounced) level of wall button, None if button is not configured
+    pub fn button_pressed(&self) -> Option<bool> {
+        self.button.as_ref().map(InputPin::is_active)
+    }
+}
+
+fn active_level(active_low: bool) -> Level {
+    if active_low {
+        Level::Low
+    } else {
+        Level::High
+    }
+}
+
+fn idle_level(active_low: bool) -> Level {
+    if active_low {
+        Level::High
+    } else {
+        Level::Low
     }
 }
@@ -1,19 +1,225 @@
+use crate::door::DoorState;
 use crate::errors::Result;
+use crate::relay::{Relay, RelayPin};
+use crate::toml::GPIO;
 use log::debug;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-pub struct Gpio;
+/// logic level of dummy pin, mirrors rppal::gpio::Level
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Low,
+    High,
+}
+
+/// dummy Gpio keeping track of pin level so that polarity
+/// handling behaves exactly like on real raspberry
+pub struct Gpio {
+    relay: Relay<OutputPin>,
+    open_sensor: Option<InputPin>,
+    closed_sensor: Option<InputPin>,
+    button: Option<InputPin>,
+}
+
+/// dummy relay output pin with configured polarity
+pub struct OutputPin {
+    level: Level,
+    active_low: bool,
+}
+
+impl OutputPin {
+    /// physical level of dummy pin
+    pub fn level(&self) -> Level {
+        self.level
+    }
+}
+
+impl RelayPin for OutputPin {
+    fn set_active(&mut self, active: bool) {
+        debug!("Setting dummy relay active: {}", active);
+        if active {
+            self.level = active_level(self.active_low);
+        } else {
+            self.level = idle_level(self.active_low);
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.level == active_level(self.active_low)
+    }
+}
+
+/// dummy sensor input pin, clones share the same state so that
+/// tests can simulate sensor changes while Gpio is owned by controller
+#[derive(Clone, Default)]
+pub struct InputPin {
+    active: Arc<AtomicBool>,
+}
+
+impl InputPin {
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    pub fn simulate(&self, active: bool) {
+        debug!("Simulating dummy sensor active: {}", active);
+        self.active.store(active, Ordering::SeqCst);
+    }
+}
 
 impl Gpio {
-    pub fn new() -> Result<Self> {
-        debug!("initiating on non-arm architecture, creating dummy Gpio handler");
-        Ok(Gpio)
+    pub fn new(config: &GPIO) -> Result<Self> {
+        debug!(
+            "initiating on non-arm architecture, creating dummy Gpio handler for pin {} (active_low: {})",
+            config.relay_pin, config.active_low
+        );
+        let output_pin = OutputPin {
+            level: idle_level(config.active_low),
+            active_low: config.active_low,
+        };
+        Ok(Gpio {
+            relay: Relay::new(output_pin, Duration::from_millis(config.max_on_ms)),
+            open_sensor: config.open_sensor_pin.map(|_| InputPin::default()),
+            closed_sensor: config.closed_sensor_pin.map(|_| InputPin::default()),
+            button: config.button_pin.map(|_| InputPin::default()),
+        })
     }
 
-    pub fn set_pin_high(&mut self) {
-        debug!("Setting dummy pin HIGH");
+    pub fn relay(&self) -> &Relay<OutputPin> {
+        &self.relay
+    }
+
+    pub fn door_state(&self) -> DoorState {
+        let (open_limit, closed_limit) = self.sensors();
+        DoorState::from_sensors(open_limit, closed_limit)
+    }
+
+    /// levels of fully open and fully closed limit sensors, None if sensor is not configured
+    pub fn sensors(&self) -> (Option<bool>, Option<bool>) {
+        (
+            self.open_sensor.as_ref().map(InputPin::is_active),
+            self.closed_sensor.as_ref().map(InputPin::is_active),
+        )
+    }
+
+    /// true if both fully open and fully closed limit sensors are configured,
+    /// i.e. door movement can be tracked
+    pub fn has_limit_sensors(&self) -> bool {
+        self.open_sensor.is_some() && self.closed_sensor.is_some()
+    }
+
+    /// raw (not debounced) level of wall button, None if button is not configured
+    pub fn button_pressed(&self) -> Option<bool> {
+        self.button.as_ref().map(InputPin::is_active)
+    }
+
+    pub fn open_sensor(&self) -> Option<&InputPin> {
+        self.open_sensor.as_ref()
+    }
+
+    pub fn closed_sensor(&self) -> Option<&InputPin> {
+        self.closed_sensor.as_ref()
+    }
+
+    pub fn button(&self) -> Option<&InputPin> {
+        self.button.as_ref()
+    }
+}
+
+fn active_level(active_low: bool) -> Level {
+    if active_low {
+        Level::Low
+    } else {
+        Level::High
+    }
+}
+
+fn idle_level(active_low: bool) -> Level {
+    if active_low {
+        Level::High
+    } else {
+        Level::Low
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // cargo test -- --show-output test_active_low_polarity
+    #[test]
+    fn test_active_low_polarity() -> Result<()> {
+        let gpio = Gpio::new(&GPIO {
+            relay_pin: 4,
+            active_low: true,
+            ..GPIO::default()
+        })?;
+        let relay = gpio.relay();
+        assert_eq!(relay.pin().level(), Level::High);
+        assert!(!relay.is_active());
+
+        let guard = relay.pulse_guard();
+        assert_eq!(relay.pin().level(), Level::Low);
+        assert!(relay.is_active());
+
+        drop(guard);
+        assert_eq!(relay.pin().level(), Level::High);
+        assert!(!relay.is_active());
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_active_high_polarity
+    #[test]
+    fn test_active_high_polarity() -> Result<()> {
+        let gpio = Gpio::new(&GPIO {
+            relay_pin: 17,
+            active_low: false,
+            ..GPIO::default()
+        })?;
+        let relay = gpio.relay();
+        assert_eq!(relay.pin().level(), Level::Low);
+
+        let guard = relay.pulse_guard();
+        assert_eq!(relay.pin().level(), Level::High);
+        assert!(relay.is_active());
+
+        drop(guard);
+        assert_eq!(relay.pin().level(), Level::Low);
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_open_sensor
+    #[test]
+    fn test_open_sensor() -> Result<()> {
+        let gpio = Gpio::new(&GPIO::default())?;
+        assert!(gpio.open_sensor().is_none());
+        assert_eq!(gpio.door_state(), DoorState::Unknown);
+
+        let gpio = Gpio::new(&GPIO {
+            open_sensor_pin: Some(17),
+            ..GPIO::default()
+        })?;
+        assert_eq!(gpio.door_state(), DoorState::Unknown);
+        gpio.open_sensor().unwrap().simulate(true);
+        assert_eq!(gpio.door_state(), DoorState::Open);
+        assert!(!gpio.has_limit_sensors());
+        Ok(())
     }
 
-    pub fn set_pin_low(&mut self) {
-        debug!("Setting dummy pin LOW");
+    // cargo test -- --show-output test_limit_sensors
+    #[test]
+    fn test_limit_sensors() -> Result<()> {
+        let gpio = Gpio::new(&GPIO {
+            open_sensor_pin: Some(17),
+            closed_sensor_pin: Some(27),
+            ..GPIO::default()
+        })?;
+        assert!(gpio.has_limit_sensors());
+        assert_eq!(gpio.door_state(), DoorState::Moving);
+        gpio.closed_sensor().unwrap().simulate(true);
+        assert_eq!(gpio.door_state(), DoorState::Closed);
+        Ok(())
     }
 }
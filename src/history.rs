@@ -0,0 +1,275 @@
+use crate::errors::Result;
+use crate::jwt::Claims;
+use crate::state;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// single record of history, e.g. received command, reply or door state change
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HistoryEntry {
+    /// unix timestamp
+    pub timestamp: u64,
+    /// 'command' for received commands, 'state' for door state changes,
+    /// otherwise name of reply or event
+    pub event: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub door: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+}
+
+impl HistoryEntry {
+    pub fn new(event: &str) -> Self {
+        HistoryEntry {
+            timestamp: state::now(),
+            event: event.to_owned(),
+            id: None,
+            door: None,
+            issuer: None,
+            command: None,
+            state: None,
+        }
+    }
+}
+
+/// signed reply to history command
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryReply {
+    #[serde(flatten)]
+    pub claims: Claims,
+    /// total number of pages
+    pub pages: usize,
+    /// entries of requested page, ordered from the newest
+    pub entries: Vec<HistoryEntry>,
+}
+
+/// Rolling history of last max_entries entries. Entries are appended to json lines
+/// file which is compacted once it holds twice as many entries as needed.
+pub struct History {
+    path: PathBuf,
+    max_entries: usize,
+    entries: VecDeque<HistoryEntry>,
+    lines_in_file: usize,
+}
+
+impl History {
+    /// loads history from given file, missing file means empty history
+    pub fn load(path: &str, max_entries: usize) -> Result<Self> {
+        let path = PathBuf::from(path);
+        let mut entries = VecDeque::new();
+        let mut lines_in_file = 0;
+        let mut damaged = false;
+        if path.exists() {
+            let content = fs::read_to_string(&path)?;
+            damaged = !content.is_empty() && !content.ends_with('\n');
+            for line in content.lines() {
+                lines_in_file += 1;
+                // last line can be torn by power cut, history is not worth refusing to start
+                match serde_json::from_str(line) {
+                    Ok(entry) => entries.push_back(entry),
+                    Err(err) => {
+                        warn!("skipping invalid history entry: {}", err);
+                        damaged = true;
+                    }
+                }
+            }
+        }
+        while entries.len() > max_entries {
+            entries.pop_front();
+        }
+        let mut history = History {
+            path,
+            max_entries,
+            entries,
+            lines_in_file,
+        };
+        // next entry would be appended to torn line otherwise
+        if damaged && max_entries > 0 {
+            if let Err(err) = history.compact() {
+                error!("unable to compact history file: {}", err.message);
+            }
+        }
+        Ok(history)
+    }
+
+    /// adds entry to history, history is best effort so write errors are only logged
+    pub fn record(&mut self, entry: HistoryEntry) {
+        if self.max_entries == 0 {
+            return;
+        }
+        if let Err(err) = self.append(&entry) {
+            error!("unable to write history file: {}", err.message);
+        }
+        self.entries.push_back(entry);
+        if self.entries.len() > self.max_entries {
+            self.entries.pop_front();
+        }
+        if self.lines_in_file > 2 * self.max_entries {
+            if let Err(err) = self.compact() {
+                error!("unable to compact history file: {}", err.message);
+            }
+        }
+    }
+
+    /// returns requested page (0 is the most recent one, entries ordered from the newest)
+    /// together with total number of pages. If door is given, only entries related
+    /// to that door (or to whole controller) are returned.
+    pub fn page(
+        &self,
+        door: Option<&str>,
+        page: usize,
+        page_size: usize,
+    ) -> (Vec<HistoryEntry>, usize) {
+        let entries: Vec<&HistoryEntry> = self
+            .entries
+            .iter()
+            .rev()
+            .filter(|entry| match (door, &entry.door) {
+                (Some(door), Some(entry_door)) => door == entry_door,
+                _ => true,
+            })
+            .collect();
+        let page_size = page_size.max(1);
+        let pages = entries.len().div_ceil(page_size);
+        let page = entries
+            .into_iter()
+            .skip(page * page_size)
+            .take(page_size)
+            .cloned()
+            .collect();
+        (page, pages)
+    }
+
+    fn append(&mut self, entry: &HistoryEntry) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(entry)?)?;
+        self.lines_in_file += 1;
+        Ok(())
+    }
+
+    /// rewrites file with entries kept in memory, write-then-rename like state file
+    fn compact(&mut self) -> Result<()> {
+        let mut content = String::new();
+        for entry in &self.entries {
+            content.push_str(&serde_json::to_string(entry)?);
+            content.push('\n');
+        }
+        state::write_atomically(&self.path, content.as_bytes())?;
+        self.lines_in_file = self.entries.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!(
+            "garage-history-{}-{}.jsonl",
+            name,
+            std::process::id()
+        ))
+    }
+
+    fn entry(id: usize, door: &str) -> HistoryEntry {
+        HistoryEntry {
+            id: Some(id.to_string()),
+            door: Some(door.to_owned()),
+            ..HistoryEntry::new("command")
+        }
+    }
+
+    // cargo test -- --show-output test_rolling_history
+    #[test]
+    fn test_rolling_history() -> Result<()> {
+        let path = temp_path("rolling");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let mut history = History::load(path, 3)?;
+        for id in 0..10 {
+            history.record(entry(id, "garage"));
+        }
+        // file is compacted, never holds more than twice as many entries as needed
+        assert!(fs::read_to_string(path)?.lines().count() <= 6);
+
+        let history = History::load(path, 3)?;
+        let (entries, pages) = history.page(None, 0, 10);
+        assert_eq!(pages, 1);
+        let ids: Vec<String> = entries.into_iter().filter_map(|e| e.id).collect();
+        assert_eq!(ids, vec!["9", "8", "7"]);
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_pages_and_door_filter
+    #[test]
+    fn test_pages_and_door_filter() -> Result<()> {
+        let path = temp_path("pages");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let mut history = History::load(path, 100)?;
+        for id in 0..5 {
+            history.record(entry(id, "left"));
+            history.record(entry(id, "right"));
+        }
+        history.record(HistoryEntry::new("startup"));
+
+        let (entries, pages) = history.page(None, 0, 4);
+        assert_eq!(pages, 3);
+        assert_eq!(entries[0].event, "startup");
+
+        let (entries, pages) = history.page(Some("left"), 1, 4);
+        assert_eq!(pages, 2);
+        let ids: Vec<String> = entries.into_iter().filter_map(|e| e.id).collect();
+        // controller wide entries (startup) are included as well
+        assert_eq!(ids, vec!["1", "0"]);
+
+        let (entries, _) = history.page(Some("left"), 5, 4);
+        assert!(entries.is_empty());
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_torn_line_skipped
+    #[test]
+    fn test_torn_line_skipped() -> Result<()> {
+        let path = temp_path("torn");
+        let line = serde_json::to_string(&entry(1, "garage"))?;
+        fs::write(&path, format!("{}\n{{\"timestamp\": 12", line))?;
+
+        let history = History::load(path.to_str().unwrap(), 10)?;
+        let (entries, _) = history.page(None, 0, 10);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, Some("1".to_owned()));
+
+        // entry recorded after torn line is not lost
+        let mut history = History::load(path.to_str().unwrap(), 10)?;
+        history.record(entry(2, "garage"));
+        let history = History::load(path.to_str().unwrap(), 10)?;
+        let (entries, _) = history.page(None, 0, 10);
+        let ids: Vec<Option<String>> = entries.into_iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, vec![Some("2".to_owned()), Some("1".to_owned())]);
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
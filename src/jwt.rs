@@ -1,8 +1,11 @@
-use crate::errors::Result;
+use crate::errors::{Error, Result};
 use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
 use std::time::SystemTime;
 
+/// issuer of commands sent by smart home fulfillment, the only issuer accepted by default
+pub const SMART_HOME_ISSUER: &str = "myhome-cc-smarthome-aog";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub iss: String,
@@ -11,11 +14,31 @@ pub struct Claims {
     pub exp: u64,
     pub iat: u64,
 
-    /// 'lock' | 'unlock' | 'toggle' | 'status'
+    /// 'lock' | 'unlock' | 'toggle' | 'open' | 'close' | 'status'
     pub command: String,
 
     /// request ID, should be returned in asynchronous response so that we can match the response to request
     pub id: String,
+
+    /// optional flags, e.g. overriding access policy restrictions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub flags: Vec<String>,
+
+    /// target door id, can be omitted if only one door listens on the command topic
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub door: Option<String>,
+
+    /// door state ('open' | 'closed' | 'moving' | 'unknown'), set in events sent by microcontroller
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+
+    /// requested page of history, 0 is the most recent one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page: Option<usize>,
+
+    /// lock state, set in status sent by microcontroller
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locked: Option<bool>,
 }
 
 impl Default for Claims {
@@ -28,13 +51,18 @@ impl Default for Claims {
         let exp_val = iat_val + 60;
 
         Claims {
-            iss: String::from("myhome-cc-smarthome-aog"),
+            iss: SMART_HOME_ISSUER.to_owned(),
             sub: String::from("myhome-cc-smarthome-microcontroller-myhome"),
             aud: String::from("myhome-cc-smarthome-microcontroller"),
             exp: exp_val,
             iat: iat_val,
             command: "".to_owned(),
             id: "".to_owned(),
+            flags: vec![],
+            door: None,
+            state: None,
+            page: None,
+            locked: None,
         }
     }
 }
@@ -47,6 +75,8 @@ impl Default for Claims {
 pub struct JWTService {
     public_key: String,
     private_key: Option<String>,
+    /// issuers accepted by verification
+    issuers: Vec<String>,
 }
 
 impl JWTService {
@@ -54,10 +84,18 @@ impl JWTService {
         JWTService {
             public_key,
             private_key,
+            issuers: vec![SMART_HOME_ISSUER.to_owned()],
         }
     }
 
-    pub fn sign(&self, payload: Claims) -> Result<String> {
+    /// accepts tokens of given issuers instead of smart home issuer only
+    pub fn with_issuers(mut self, issuers: Vec<String>) -> Self {
+        self.issuers = issuers;
+        self
+    }
+
+    /// signs Claims or any other payload (e.g. reply carrying list of history entries)
+    pub fn sign<T: Serialize>(&self, payload: T) -> Result<String> {
         let token = encode(
             &Header::new(Algorithm::RS256),
             &payload,
@@ -68,12 +106,22 @@ impl JWTService {
         Ok(token)
     }
 
+    /// parses configured keys so that invalid PEM files are detected before the service is used
+    pub fn check_keys(&self) -> Result<()> {
+        DecodingKey::from_rsa_pem(self.public_key.as_bytes())?;
+        if let Some(private_key) = &self.private_key {
+            EncodingKey::from_rsa_pem(private_key.as_bytes())?;
+        }
+        Ok(())
+    }
+
     pub fn verify(&self, token: &str, validate_expiry: bool) -> Result<Claims> {
         let mut aud = std::collections::HashSet::new();
         aud.insert("myhome-cc-smarthome-microcontroller".to_owned());
 
+        // issuer is checked below, jsonwebtoken accepts single issuer only
         let validation = Validation {
-            iss: Some("myhome-cc-smarthome-aog".to_owned()),
+            iss: None,
             sub: Some("myhome-cc-smarthome-microcontroller-myhome".to_owned()),
             aud: Some(aud),
             validate_exp: validate_expiry,
@@ -83,10 +131,16 @@ impl JWTService {
         };
 
         let token_data = decode::<Claims>(
-            &token,
+            token,
             &DecodingKey::from_rsa_pem(&self.public_key.to_owned().into_bytes())?,
             &validation,
         )?;
+        if !self.issuers.contains(&token_data.claims.iss) {
+            return Err(Error::new(format!(
+                "token issuer '{}' is not accepted",
+                token_data.claims.iss
+            )));
+        }
         Ok(token_data.claims)
     }
 }
@@ -94,17 +148,7 @@ impl JWTService {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use lazy_static::lazy_static;
-    use std::fs;
-
-    lazy_static! {
-        pub static ref SMART_HOME_ACTION_PRIVATE_KEY: String =
-            fs::read_to_string("./examples/testdata/smart-home-priv.pem").unwrap_or("".to_owned());
-        pub static ref SMART_HOME_ACTION_PUBLIC_KEY: String =
-            fs::read_to_string("./examples/testdata/smart-home-pub.pem").unwrap_or("".to_owned());
-        pub static ref SMART_HOME_ACTION_REAL_TOKEN: String =
-            fs::read_to_string("./examples/testdata/smart_home_token.txt").unwrap_or("".to_owned());
-    }
+    use crate::fixtures::{self, MICROCONTROLLER_KEYS, SMART_HOME_KEYS};
 
     // https://travistidwell.com/jsencrypt/demo/
     // SAMPLE_PRIVATE_KEY and SAMPLE_PUBLIC_KEY are 1024 bit long
@@ -227,26 +271,65 @@ MwIDAQAB
         let claims = jwt_svc_verif.verify(&token, true);
 
         match claims {
-            Ok(claims) => panic!(format!(
+            Ok(claims) => panic!(
                 "test_sign_corrupt_fail_to_verify expected error, got claims: {:#?}",
                 claims
-            )),
-            Err(error) => assert_eq!(
-                error.message.contains("Base64 error: Invalid last symbol"),
-                true
             ),
+            Err(error) => assert!(error.message.contains("Base64 error: Invalid last symbol")),
         }
 
         Ok(())
     }
 
-    // cargo test -- --show-output test_verify_with_real_cert
+    // cargo test -- --show-output test_check_keys
+    #[test]
+    fn test_check_keys() {
+        let jwt_svc = JWTService::new(
+            SAMPLE_PUBLIC_KEY_2048.to_owned(),
+            Some(SAMPLE_PRIVATE_KEY_2048.to_owned()),
+        );
+        assert!(jwt_svc.check_keys().is_ok());
+
+        let jwt_svc = JWTService::new(SAMPLE_PUBLIC_KEY_2048.to_owned(), Some("".to_owned()));
+        assert!(jwt_svc.check_keys().is_err());
+
+        let jwt_svc = JWTService::new("-----BEGIN PUBLIC KEY-----".to_owned(), None);
+        assert!(jwt_svc.check_keys().is_err());
+    }
+
+    // cargo test -- --show-output test_verify_generated_keys
     #[test]
-    #[ignore]
-    fn test_verify_with_real_cert() -> Result<()> {
-        let jwt_svc_verif = JWTService::new(SMART_HOME_ACTION_PUBLIC_KEY.to_owned(), None);
-        let claims = jwt_svc_verif.verify(&SMART_HOME_ACTION_REAL_TOKEN, false)?;
+    fn test_verify_generated_keys() -> Result<()> {
+        let token = fixtures::signed_command(fixtures::command("toggle", "42"))?;
+        let jwt_svc_verif = SMART_HOME_KEYS.verif();
+        jwt_svc_verif.check_keys()?;
+        let claims = jwt_svc_verif.verify(&token, false)?;
         println!("claims {:#?}", claims);
+        assert_eq!(claims.command, "toggle");
+        assert_eq!(claims.id, "42");
+
+        // token signed by other party is rejected
+        assert!(MICROCONTROLLER_KEYS.verif().verify(&token, false).is_err());
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_accepted_issuers
+    #[test]
+    fn test_accepted_issuers() -> Result<()> {
+        let token = fixtures::signed_command(Claims {
+            iss: "myhome-cc-smarthome-admin".to_owned(),
+            ..fixtures::command("unlock", "43")
+        })?;
+        let err = SMART_HOME_KEYS.verif().verify(&token, true).unwrap_err();
+        assert!(err.message.contains("issuer 'myhome-cc-smarthome-admin'"));
+
+        let jwt_svc_verif = SMART_HOME_KEYS.verif().with_issuers(vec![
+            SMART_HOME_ISSUER.to_owned(),
+            "myhome-cc-smarthome-admin".to_owned(),
+        ]);
+        assert_eq!(jwt_svc_verif.verify(&token, true)?.command, "unlock");
+        let token = fixtures::signed_command(fixtures::command("toggle", "44"))?;
+        assert_eq!(jwt_svc_verif.verify(&token, true)?.command, "toggle");
         Ok(())
     }
 
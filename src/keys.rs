@@ -0,0 +1,63 @@
+use crate::aes;
+use crate::errors::{Error, Result};
+use crate::jwt::{Claims, JWTService};
+use crate::toml::ApplicationConfiguration;
+use std::fs;
+
+/// Key material referenced by configuration. Keys are validated when loaded
+/// so that invalid files never replace keys which are in use.
+pub struct Keys {
+    pub aes_key: String,
+    /// verifies commands signed by smart home
+    pub jwt_svc_verif: JWTService,
+    /// signs replies and events of microcontroller
+    pub jwt_svc_signing: JWTService,
+}
+
+impl Keys {
+    pub fn load(config: &ApplicationConfiguration) -> Result<Self> {
+        aes::check_key(&config.aes.key)?;
+        let (jwt_svc_verif, jwt_svc_signing) = load_jwt(config)?;
+        Ok(Keys {
+            aes_key: config.aes.key.to_owned(),
+            jwt_svc_verif,
+            jwt_svc_signing,
+        })
+    }
+}
+
+/// services verifying smart home commands and signing microcontroller replies,
+/// microcontroller private key must match its public key
+pub(crate) fn load_jwt(config: &ApplicationConfiguration) -> Result<(JWTService, JWTService)> {
+    let jwt_svc_verif = JWTService::new(
+        read_key(&config.smart_home.pub_key, "smart home public key")?,
+        None,
+    )
+    .with_issuers(config.smart_home.issuers.clone());
+    jwt_svc_verif.check_keys()?;
+
+    let jwt_svc_signing = JWTService::new(
+        read_key(
+            &config.microcontroller.pub_key,
+            "microcontroller public key",
+        )?,
+        Some(read_key(
+            &config.microcontroller.priv_key,
+            "microcontroller private key",
+        )?),
+    );
+    jwt_svc_signing.check_keys()?;
+    // token signed by private key must be verifiable by public key
+    let token = jwt_svc_signing.sign(Claims::default())?;
+    if jwt_svc_signing.verify(&token, true).is_err() {
+        return Err(Error::new(
+            "microcontroller private key does not match public key".to_owned(),
+        ));
+    }
+    Ok((jwt_svc_verif, jwt_svc_signing))
+}
+
+pub(crate) fn read_key(path: &str, description: &str) -> Result<String> {
+    fs::read_to_string(path)
+        .map_err(|err| Error::new(format!("unable to load {} {}: {}", description, path, err)))
+}
@@ -4,8 +4,16 @@ use log4rs::config::{Appender, Config, Root};
 use std::env::current_exe;
 
 pub mod aes;
+pub mod availability;
+#[cfg(test)]
+pub(crate) mod broker;
+pub mod button;
 pub mod cli;
+pub mod controller;
+pub mod door;
 pub mod errors;
+#[cfg(test)]
+pub(crate) mod fixtures;
 
 #[cfg(all(target_family = "unix", target_arch = "arm"))]
 pub mod gpio_arm;
@@ -19,9 +27,21 @@ pub use gpio_arm as gpio;
 #[cfg(not(all(target_family = "unix", target_arch = "arm")))]
 pub use gpio_mock as gpio;
 
+pub mod history;
 pub mod jwt;
+pub mod keys;
 pub mod mqtt;
+pub mod policy;
+pub mod provision;
+pub mod ratelimit;
+pub mod relay;
+pub mod selftest;
+pub mod simulator;
+pub mod state;
+pub mod systemd;
 pub mod toml;
+pub mod tools;
+pub mod validate;
 
 fn init_with_default_logging_config() {
     let stdout = ConsoleAppender::builder().build();
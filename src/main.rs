@@ -1,23 +1,35 @@
-use ctrlc;
 use garage_controller::{
-    aes,
-    cli::{get_cmd_line_parser, get_cmdl_options},
+    availability::Availability,
+    cli::{get_cmd_line_parser, get_cmdl_options, Command},
+    controller::{self, Controller},
     errors::{Error, Result},
-    gpio, jwt, mqtt,
+    gpio::Gpio,
+    jwt::JWTService,
+    keys::Keys,
+    mqtt, provision, selftest, simulator,
+    systemd::Notifier,
     toml::ApplicationConfiguration,
+    tools,
+    validate::validate,
 };
-use log::{debug, trace};
+use log::{debug, error, info, trace, warn};
 use mqtt_async_client::client::{Client, QoS, Subscribe, SubscribeTopic};
+use std::future::Future;
+use std::io::{self, Write};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::{fs, process, sync::Arc};
-use tokio::time::{delay_for, timeout, Duration};
+use std::time::Instant;
+use std::{path::Path, process, sync::Arc};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::oneshot;
+use tokio::time::{timeout, Duration};
 
 ///
 /// Convenience macro to replace following boilerplate:
 ///
 /// if let Err(err) = result {
-///    debug!("unable to load smart home public key");
-///    debug!("error detail {}", err);
+///    error!("unable to load smart home public key");
+///    error!("error detail {}", err);
 ///    process::exit(1);
 /// }
 ///
@@ -27,48 +39,273 @@ use tokio::time::{delay_for, timeout, Duration};
 macro_rules! eval_error {
     ($result:expr, $msg:expr) => {
         if let Err(err) = $result {
-            debug!($msg);
-            debug!("error detail {}", err);
+            error!($msg);
+            error!("error detail {}", err);
             process::exit(1);
         }
     };
 }
 
-fn main() -> Result<()> {
+/// re-reads configuration and keys, returns new configuration, aes key and verification
+/// service if everything is valid and already applied to controller
+fn reload(
+    config_path: &str,
+    controller: &mut Controller,
+) -> Result<(ApplicationConfiguration, String, JWTService)> {
+    let config = ApplicationConfiguration::new(config_path)?;
+    let keys = validate(&config).map_err(|problems| Error::new(problems.join("; ")))?;
+    controller.reload(&config, keys.jwt_svc_signing)?;
+    Ok((config, keys.aes_key, keys.jwt_svc_verif))
+}
 
-    let cmd_line_matches = get_cmd_line_parser().get_matches();
-    let cmd_line_opts = get_cmdl_options(&cmd_line_matches);
+/// prints all problems of configuration, returns process exit code
+fn check_config(config_path: &str) -> i32 {
+    let config = match ApplicationConfiguration::new(config_path) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("unable to load configuration {}: {}", config_path, err);
+            return 1;
+        }
+    };
+    let problems = match validate(&config) {
+        Ok(_) => {
+            println!("configuration {} is valid", config_path);
+            return 0;
+        }
+        Err(problems) => problems,
+    };
+    eprintln!(
+        "configuration {} has {} problem(s):",
+        config_path,
+        problems.len()
+    );
+    for problem in problems {
+        eprintln!("  - {}", problem);
+    }
+    1
+}
 
-    debug!("Starting microcontroller");
-    garage_controller::init_logging();
+/// loads configuration, runs tool (encrypt, decrypt, sign, verify, inspect or send)
+/// and prints its result, returns process exit code
+fn run_tool<F>(config_path: &str, tool: F) -> i32
+where
+    F: FnOnce(&ApplicationConfiguration) -> Result<String>,
+{
+    let config = match ApplicationConfiguration::new(config_path) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("unable to load configuration {}: {}", config_path, err);
+            return 1;
+        }
+    };
+    let result = tool(&config);
+    match result {
+        Ok(output) => {
+            println!("{}", output);
+            0
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
 
-    #[allow(non_snake_case)]
-    let APP_CONFIG: ApplicationConfiguration =
-        ApplicationConfiguration::new(cmd_line_opts.app_config_path.to_str().unwrap())?;
+/// generates keys and configuration, prints material needed by the cloud side,
+/// returns process exit code
+fn provision(config_path: &str, options: &provision::ProvisionOptions) -> i32 {
+    let provisioned = match provision::provision(Path::new(config_path), options) {
+        Ok(provisioned) => provisioned,
+        Err(err) => {
+            eprintln!("unable to provision controller: {}", err);
+            return 1;
+        }
+    };
+    for file in &provisioned.files {
+        println!("written {}", file.display());
+    }
+    println!();
+    println!("microcontroller public key (smart home verifies replies by it):");
+    println!("{}", provisioned.public_key);
+    println!("AES key (shared secret, smart home encrypts commands by it):");
+    println!("{}", provisioned.aes_key);
+    println!();
+    if !provisioned.smart_home_key_found {
+        println!(
+            "copy smart home public key to {} referenced by configuration",
+            provisioned.smart_home_pub_key.display()
+        );
+    }
+    println!(
+        "fill in [mqtt] section of {} and run check-config subcommand",
+        config_path
+    );
+    0
+}
 
-    #[allow(non_snake_case)]
-    let SMART_HOME_ACTION_PUBLIC_KEY: String = {
-        let result = fs::read_to_string(APP_CONFIG.smart_home.pub_key.to_owned());
-        eval_error!(result, "unable to load smart home public key");
-        result.unwrap()
+/// pulses relay of configured door, returns process exit code
+fn test_relay(config_path: &str, options: &selftest::RelayTestOptions) -> i32 {
+    let door = ApplicationConfiguration::new(config_path)
+        .and_then(|config| selftest::select_door(&config, options.door.as_deref()));
+    let door = match door {
+        Ok(door) => door,
+        Err(err) => {
+            eprintln!("unable to load configuration {}: {}", config_path, err);
+            return 1;
+        }
     };
+    let gpio = match Gpio::new(&door.gpio) {
+        Ok(gpio) => gpio,
+        Err(err) => {
+            eprintln!("unable to initialize gpio: {}", err);
+            return 1;
+        }
+    };
+    gpio.relay().install_panic_hook();
 
-    #[allow(non_snake_case)]
-    let MICROCONTROLLER_PUBLIC_KEY: String = {
-        let result = fs::read_to_string(APP_CONFIG.microcontroller.pub_key.to_owned());
-        eval_error!(result, "unable to load microcontroller public key");
-        result.unwrap()
+    let confirm = |prompt: &str| {
+        print!("{}. Continue? [y/N] ", prompt);
+        let _ = io::stdout().flush();
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer).is_ok() && answer.trim().eq_ignore_ascii_case("y")
     };
+    let results = selftest::test_relay(&gpio, &door, options, confirm, |result| {
+        println!("{}", result)
+    });
+    if results.is_empty() {
+        println!("no pulse performed");
+        return 1;
+    }
+    if results.iter().any(|result| result.failed()) {
+        return 1;
+    }
+    0
+}
 
-    #[allow(non_snake_case)]
-    let MICROCONTROLLER_PRIV_KEY: String = {
-        let result = fs::read_to_string(APP_CONFIG.microcontroller.priv_key.to_owned());
-        eval_error!(result, "unable to load microcontroller private key");
-        result.unwrap()
+/// sends command as smart home would do, returns report of received replies
+fn send(config: &ApplicationConfiguration, options: &simulator::SendOptions) -> Result<String> {
+    let mut rt = tokio::runtime::Runtime::new()?;
+    let replies = rt.block_on(simulator::send(config, options))?;
+    let mut report: Vec<String> = replies
+        .iter()
+        .map(|reply| {
+            format!(
+                "{} ms: {}",
+                reply.elapsed.as_millis(),
+                serde_json::to_string(&reply.claims).unwrap_or_default()
+            )
+        })
+        .collect();
+    if !replies.last().is_some_and(simulator::Reply::is_final) {
+        report.push(format!(
+            "no final reply within {} s",
+            options.timeout.as_secs()
+        ));
+        return Err(Error::new(report.join("\n")));
+    }
+    Ok(report.join("\n"))
+}
+
+/// name of received shutdown signal and when it was received
+type Signal = (&'static str, Instant);
+
+/// whole graceful shutdown must finish within this time after signal is received
+fn shutdown_deadline(
+    reloaded: &Option<ApplicationConfiguration>,
+    config: &ApplicationConfiguration,
+) -> Duration {
+    Duration::from_secs(reloaded.as_ref().unwrap_or(config).shutdown.deadline_secs)
+}
+
+/// Runs step of main loop (tick or handling of received message). If shutdown signal arrives
+/// meanwhile, the step (e.g. relay pulse in progress) is still finished, but at most within
+/// shutdown deadline so that unresponsive broker cannot block the shutdown. Returns received signal.
+async fn finish_step<F>(
+    step: F,
+    shutdown_rx: &mut oneshot::Receiver<Signal>,
+    deadline: Duration,
+) -> Result<Option<Signal>>
+where
+    F: Future<Output = Result<()>>,
+{
+    tokio::pin!(step);
+    let (name, received) = tokio::select! {
+        result = &mut step => return result.map(|_| None),
+        Ok(signal) = &mut *shutdown_rx => signal,
     };
+    info!("{} received, finishing command being processed", name);
+    match timeout(deadline, step).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => error!("command being processed failed: {}", err),
+        Err(_) => error!(
+            "command being processed did not finish within {:?}",
+            deadline
+        ),
+    }
+    Ok(Some((name, received)))
+}
+
+fn main() -> Result<()> {
+    let cmd_line_matches = get_cmd_line_parser().get_matches();
+    let cmd_line_opts = get_cmdl_options(&cmd_line_matches);
+    let config_path = cmd_line_opts.app_config_path.to_str().unwrap().to_owned();
+
+    match cmd_line_opts.command {
+        Command::Run => {}
+        Command::CheckConfig => process::exit(check_config(&config_path)),
+        Command::Provision(ref options) => process::exit(provision(&config_path, options)),
+        Command::TestRelay(ref options) => process::exit(test_relay(&config_path, options)),
+        Command::Encrypt { ref input } => process::exit(run_tool(&config_path, |config| {
+            tools::read_input(input.as_deref()).and_then(|input| tools::encrypt(config, &input))
+        })),
+        Command::Decrypt { ref input } => process::exit(run_tool(&config_path, |config| {
+            tools::read_input(input.as_deref()).and_then(|input| tools::decrypt(config, &input))
+        })),
+        Command::Sign { ref input } => process::exit(run_tool(&config_path, |config| {
+            tools::read_input(input.as_deref()).and_then(|input| tools::sign(config, &input))
+        })),
+        Command::Verify {
+            ref input,
+            signer,
+            validate_expiry,
+        } => process::exit(run_tool(&config_path, |config| {
+            tools::read_input(input.as_deref())
+                .and_then(|input| tools::verify(config, &input, signer, validate_expiry))
+        })),
+        Command::Inspect { ref input } => process::exit(run_tool(&config_path, |config| {
+            tools::read_input(input.as_deref()).and_then(|input| tools::inspect(config, &input))
+        })),
+        Command::Send(ref options) => {
+            process::exit(run_tool(&config_path, |config| send(config, options)))
+        }
+    }
+
+    garage_controller::init_logging();
+    debug!("Starting microcontroller");
 
+    let app_config = ApplicationConfiguration::new(&config_path);
+    eval_error!(app_config, "unable to load configuration");
     #[allow(non_snake_case)]
-    let AES_KEY: String = APP_CONFIG.aes.key.to_owned();
+    let APP_CONFIG: ApplicationConfiguration = app_config.unwrap();
+
+    let keys = match validate(&APP_CONFIG) {
+        Ok(keys) => keys,
+        Err(problems) => {
+            for problem in &problems {
+                error!("invalid configuration: {}", problem);
+            }
+            eprintln!(
+                "invalid configuration, run check-config subcommand for details: {}",
+                problems.join("; ")
+            );
+            process::exit(1);
+        }
+    };
+    let Keys {
+        mut aes_key,
+        mut jwt_svc_verif,
+        jwt_svc_signing,
+    } = keys;
 
     debug!(
         "SMART_HOME_ACTION_PUBLIC_KEY: {}",
@@ -96,87 +333,182 @@ fn main() -> Result<()> {
 
     let mut c = c.unwrap();
 
+    // readiness and watchdog of systemd service, see examples/garage-controller.service
+    let mut notifier = Notifier::from_env();
+    let server = format!("{}:{}", APP_CONFIG.mqtt.host, APP_CONFIG.mqtt.port);
+
     rt.unwrap().block_on(async {
+        notifier.status(&format!("connecting to MQTT server {}", server));
         let conn_result = c.connect().await;
+        if let Err(err) = &conn_result {
+            notifier.status(&format!(
+                "unable to connect to MQTT server {}: {}",
+                server, err
+            ));
+        }
         eval_error!(conn_result, "unable to connect to MQTT server");
+        // retained online message, broker publishes offline when controller disappears
+        let availability = Availability::start(&APP_CONFIG.mqtt);
+
+        // SIGTERM and SIGINT (Ctrl-C) stop the main loop and start graceful shutdown
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<Signal>();
+        #[cfg(unix)]
+        {
+            let mut terminate = signal(SignalKind::terminate())?;
+            let mut interrupt = signal(SignalKind::interrupt())?;
+            tokio::spawn(async move {
+                let name = tokio::select! {
+                    _ = terminate.recv() => "SIGTERM",
+                    _ = interrupt.recv() => "SIGINT",
+                };
+                let _ = shutdown_tx.send((name, Instant::now()));
+            });
+        }
+        #[cfg(not(unix))]
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                let _ = shutdown_tx.send(("CTRL_C_EVENT", Instant::now()));
+            }
+        });
+
+        // SIGHUP re-reads configuration and keys
+        let reload_requested = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        {
+            let r = reload_requested.clone();
+            let mut hangup = signal(SignalKind::hangup())?;
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    debug!("SIGHUP detected, configuration will be reloaded");
+                    r.store(true, Ordering::SeqCst);
+                }
+            });
+        }
 
-        let subopts = Subscribe::new(vec![SubscribeTopic {
-            qos: QoS::AtMostOnce,
-            topic_path: "garage/toggle".to_owned(),
-        }]);
+        // relay pins of all doors are set to their idle level already during initialization
+        let mut controller = Controller::new(&APP_CONFIG, jwt_svc_signing)?;
+
+        let command_topics = controller.command_topics();
+        let subopts = Subscribe::new(
+            command_topics
+                .iter()
+                .map(|topic| SubscribeTopic {
+                    qos: QoS::AtMostOnce,
+                    topic_path: topic.to_owned(),
+                })
+                .collect(),
+        );
         let subres = c.subscribe(subopts).await?;
         subres.any_failures()?;
+        controller
+            .publish_status(controller::event_id("startup"), &c)
+            .await?;
+        let ready_status = format!(
+            "connected to MQTT server {}, waiting for commands on {}",
+            server,
+            command_topics.join(", ")
+        );
+        notifier.ready(&ready_status);
 
-        let mut gpio = gpio::Gpio::new()?;
-
-        // set the PIN initially LOW
-        debug!("setting relay PIN initially LOW");
-        gpio.set_pin_low();
-        debug!("setting relay PIN initially LOW - done");
+        // settings which can be reloaded are taken from the last reloaded configuration
+        let mut reloaded: Option<ApplicationConfiguration> = None;
 
-        let running = Arc::new(AtomicBool::new(true));
-        let r = running.clone();
+        debug!("Starting main processing loop!");
+        let (name, received) = loop {
+            // wedged loop stops pinging and systemd restarts the controller
+            notifier.watchdog(Instant::now());
+            for _ in 0..availability.take_reconnects() {
+                controller.record_availability_reconnect();
+            }
+            let deadline = shutdown_deadline(&reloaded, &APP_CONFIG);
 
-        ctrlc::set_handler(move || {
-            debug!("SIGINT/CTRL_C_EVENT/CTRL_BREAK_EVENT detected, terminating main loop");
-            r.store(false, Ordering::SeqCst);
-        })
-        .expect("Error setting Ctrl-C handler");
+            // queued command is pulsed by tick, it is finished even if signal arrives meanwhile
+            if let Some(signal) =
+                finish_step(controller.tick(&c), &mut shutdown_rx, deadline).await?
+            {
+                break signal;
+            }
 
-        let jwt_svc_verif = jwt::JWTService::new(SMART_HOME_ACTION_PUBLIC_KEY.to_owned(), None);
-        let jwt_svc_signing = jwt::JWTService::new(
-            MICROCONTROLLER_PUBLIC_KEY.to_owned(),
-            Some(MICROCONTROLLER_PRIV_KEY.to_owned()),
-        );
+            if reload_requested.swap(false, Ordering::SeqCst) {
+                notifier.reloading();
+                match reload(&config_path, &mut controller) {
+                    Ok((config, new_aes_key, new_jwt_svc_verif)) => {
+                        aes_key = new_aes_key;
+                        jwt_svc_verif = new_jwt_svc_verif;
+                        info!("configuration and keys reloaded");
+                        let ignored = APP_CONFIG.restart_required(&config);
+                        if !ignored.is_empty() {
+                            warn!(
+                                "changes of {} are not applied until restart",
+                                ignored.join(", ")
+                            );
+                        }
+                        reloaded = Some(config);
+                    }
+                    Err(err) => error!(
+                        "unable to reload configuration, keeping previous one: {}",
+                        err
+                    ),
+                }
+                notifier.ready(&ready_status);
+            }
 
-        debug!("Starting main processing loop!");
-        while running.load(Ordering::SeqCst) {
-            trace!("waiting for new messages on topic garage/toggle");
+            trace!("waiting for new messages on topics {:?}", command_topics);
 
-            // Read subscription with timeout to enable ctrl+c to be handled continuously
-            let r = timeout(Duration::from_secs(1), mqtt::read_subscriptions(&mut c)).await;
+            // Read subscription with timeout so that queued commands (and wall buttons) are handled
+            // continuously. Signal interrupts only the waiting, message which was already read
+            // is processed (bounded by shutdown deadline) before shutdown starts.
+            let wait_time = controller
+                .next_deadline()
+                .map_or(Duration::from_secs(1), |deadline| {
+                    deadline.min(Duration::from_secs(1))
+                })
+                .min(notifier.watchdog_interval().unwrap_or(Duration::MAX));
+            let r = tokio::select! {
+                Ok(signal) = &mut shutdown_rx => break signal,
+                r = timeout(wait_time, mqtt::read_subscriptions(&mut c)) => r,
+            };
             if r.is_err() {
-                trace!("read_subscriptions timeout, continuing to allow potential ctrlc.");
+                trace!("read_subscriptions timeout, continuing to handle queued commands.");
                 continue;
             }
             let r = r.unwrap();
+            if let Err(err) = &r {
+                notifier.status(&format!(
+                    "unable to read from MQTT server {}: {}",
+                    server, err
+                ));
+            }
             eval_error!(r, "unable to read subscriptions from MQTT server");
             let r = r.unwrap();
 
-            // Read subscription in "blocking/awaiting way" with no timeout.
-            // asynchronous block will be waiting here forever unless it receives
-            // mqtt message to process (preventing ctrl+c condition in while loop being evaluated)
-            // this works fine until we want to support ctrl+c handler
-            // let r = mqtt::read_subscriptions(&mut c).await?;
-            assert_eq!(r.topic(), "garage/toggle");
-
-            let payload = String::from_utf8(r.payload().to_vec())?;
-            debug!("original payload from mqtt {}", payload);
-
-            let decrypted_payload = aes::decrypt(&payload, &AES_KEY)?;
-            debug!("decrypted payload from mqtt {}", decrypted_payload);
-
-            let claims = jwt_svc_verif.verify(&decrypted_payload, true)?;
-            debug!("token verified. claims {:#?}", claims);
-
-            let confirmation_payload = jwt::Claims {
-                command: "confirmation".to_owned(),
-                id: claims.id,
-                ..jwt::Claims::default()
-            };
-
-            let confirmation_token = jwt_svc_signing.sign(confirmation_payload)?;
-            debug!("acknowledgment prepared {}", confirmation_token);
-
-            debug!("setting pin high");
-            gpio.set_pin_high();
-            delay_for(Duration::from_millis(400)).await;
-            gpio.set_pin_low();
-            debug!("setting pin low, sending acknowledgment to smart-home");
+            let handling =
+                controller.handle_message(r.topic(), r.payload(), &aes_key, &jwt_svc_verif, &c);
+            if let Some(signal) = finish_step(handling, &mut shutdown_rx, deadline).await? {
+                break signal;
+            }
+        }; // main microcontroller loop
+        info!("{} received, shutting down", name);
+        notifier.stopping(&format!("{} received, shutting down", name));
 
-            mqtt::publish(confirmation_token, "garage/toggleConfirm".to_owned(), &c).await?;
-            debug!("acknowledgment sent!");
-        } // main microcontroller loop
+        // broker which does not respond must not keep the controller running,
+        // deadline includes finishing of command being processed when signal arrived
+        let shutdown_config = &reloaded.as_ref().unwrap_or(&APP_CONFIG).shutdown;
+        let deadline = Duration::from_secs(shutdown_config.deadline_secs)
+            .checked_sub(received.elapsed())
+            .unwrap_or_default();
+        let drain = Duration::from_secs(shutdown_config.drain_secs);
+        let shutdown = async {
+            controller.shutdown(drain, &c).await?;
+            availability.stop().await;
+            c.disconnect().await?;
+            Ok::<(), Error>(())
+        };
+        match timeout(deadline, shutdown).await {
+            Ok(Ok(())) => info!("final status published, disconnected from MQTT server"),
+            Ok(Err(err)) => error!("graceful shutdown failed: {}", err),
+            Err(_) => error!("graceful shutdown did not finish within {:?}", deadline),
+        }
 
         // just so that async block return value can be infered
         // currently no way how to specify async block ret value like for asyn fn, must use turbo fish
@@ -184,5 +516,7 @@ fn main() -> Result<()> {
         Ok::<(), Error>(())
     })?;
     debug!("main processing loop finished, quiting now. bye!");
+    // state and history are written on every change, only buffered log records are left
+    log::logger().flush();
     Ok(())
 }
@@ -4,6 +4,17 @@ use mqtt_async_client::{
 };
 use tokio::{self, time::Duration};
 
+/// topic where smart home publishes encrypted commands
+pub const TOGGLE_TOPIC: &str = "garage/toggle";
+/// topic where microcontroller publishes signed replies
+pub const TOGGLE_CONFIRM_TOPIC: &str = "garage/toggleConfirm";
+/// topic where microcontroller publishes signed events not triggered by command, e.g. alerts
+pub const EVENTS_TOPIC: &str = "garage/events";
+/// topic where microcontroller publishes signed status, i.e. door and lock state
+pub const STATUS_TOPIC: &str = "garage/status";
+/// topic where microcontroller publishes retained online/offline message
+pub const AVAILABILITY_TOPIC: &str = "garage/availability";
+
 pub fn plain_client(
     host: &str,
     port: u16,
@@ -33,60 +44,43 @@ pub async fn publish(data: String, topic: String, c: &Client) -> mqtt_async_clie
     Ok(())
 }
 
+/// publishes message which broker keeps for future subscribers
+pub async fn publish_retained(
+    data: String,
+    topic: String,
+    c: &Client,
+) -> mqtt_async_client::Result<()> {
+    let mut p = Publish::new(topic, data.as_bytes().to_vec());
+    p.set_qos(QoS::AtMostOnce);
+    p.set_retain(true);
+    c.publish(&p).await?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::aes::decrypt;
+    use crate::broker::Broker;
     use crate::errors::Result;
+    use crate::fixtures::{self, AES_KEY, MICROCONTROLLER_KEYS, SMART_HOME_KEYS};
     use crate::gpio;
     use crate::init_logging;
-    use crate::jwt::{Claims, JWTService};
-    use crate::toml::{ApplicationConfiguration, MQTT};
-    use lazy_static::lazy_static;
+    use crate::jwt::Claims;
+    use crate::toml::GPIO;
     use log::info;
     use mqtt_async_client::client::{Publish, QoS, Subscribe, SubscribeTopic};
     use std::default::Default;
-    use std::fs;
     use std::time::Duration;
-    use tokio::time::delay_for;
-    use toml;
-
-    lazy_static! {
-        pub static ref SMART_HOME_ACTION_PUBLIC_KEY: String =
-            fs::read_to_string("./examples/testdata/smart-home-pub.pem").unwrap_or("".to_owned());
-        pub static ref AES_KEY: String =
-            fs::read_to_string("./examples/testdata/aes.txt").unwrap_or("".to_owned());
-        pub static ref MQTT_CONN: MQTT = {
-            fn get_mqtt_config() -> crate::errors::Result<MQTT> {
-                let toml_str = std::fs::read_to_string("./examples/testdata/app_config.toml")?;
-                let toml = toml::from_str::<ApplicationConfiguration>(&toml_str)?;
-                Ok(toml.mqtt)
-            }
-
-            let mqtt = get_mqtt_config().unwrap_or_default();
-            mqtt
-        };
-        pub static ref MICROCONTROLLER_PUBLIC_KEY: String =
-            fs::read_to_string("./examples/testdata/microcontroller-pubkey.pem")
-                .unwrap_or("".to_owned());
-        pub static ref MICROCONTROLLER_PRIVATE_KEY: String =
-            fs::read_to_string("./examples/testdata/microcontroller-privkey.pem")
-                .unwrap_or("".to_owned());
-    }
 
     // cargo test -- --show-output test_plain_client
     #[test]
-    #[ignore]
     fn test_plain_client() -> Result<()> {
         let mut rt = tokio::runtime::Runtime::new()?;
 
         let result = rt.block_on(async {
-            let mut c = plain_client(
-                &MQTT_CONN.host,
-                MQTT_CONN.port,
-                &MQTT_CONN.username,
-                &MQTT_CONN.password,
-            )?;
+            let broker = Broker::start().await?;
+            let mut c = plain_client(&broker.host(), broker.port(), "user", "password")?;
             c.connect().await?;
             println!("connected");
             c.disconnect().await?;
@@ -98,16 +92,11 @@ mod tests {
 
     // cargo test -- --show-output test_pub_and_sub
     #[test]
-    #[ignore]
     fn test_pub_and_sub() -> Result<()> {
         let mut rt = tokio::runtime::Runtime::new()?;
         rt.block_on(async {
-            let mut c = plain_client(
-                &MQTT_CONN.host,
-                MQTT_CONN.port,
-                &MQTT_CONN.username,
-                &MQTT_CONN.password,
-            )?;
+            let broker = Broker::start().await?;
+            let mut c = plain_client(&broker.host(), broker.port(), "user", "password")?;
             c.connect().await?;
 
             // Subscribe
@@ -133,19 +122,46 @@ mod tests {
         })
     }
 
+    // cargo test -- --show-output test_publish_retained
+    #[test]
+    fn test_publish_retained() -> Result<()> {
+        let mut rt = tokio::runtime::Runtime::new()?;
+        rt.block_on(async {
+            let broker = Broker::start().await?;
+            let mut c = plain_client(&broker.host(), broker.port(), "user", "password")?;
+            c.connect().await?;
+            publish_retained("open".to_owned(), STATUS_TOPIC.to_owned(), &c).await?;
+            publish("ignored".to_owned(), EVENTS_TOPIC.to_owned(), &c).await?;
+            c.disconnect().await?;
+
+            // status is delivered to subscriber which connects later
+            let mut c = plain_client(&broker.host(), broker.port(), "user", "password")?;
+            c.connect().await?;
+            let subres = c
+                .subscribe(Subscribe::new(vec![SubscribeTopic {
+                    qos: QoS::AtMostOnce,
+                    topic_path: "garage/#".to_owned(),
+                }]))
+                .await?;
+            subres.any_failures()?;
+            let r = c.read_subscriptions().await?;
+            assert_eq!(r.topic(), STATUS_TOPIC);
+            assert_eq!(r.payload(), b"open");
+            assert_eq!(broker.published().len(), 2);
+            assert!(broker.retained(EVENTS_TOPIC).is_none());
+            c.disconnect().await?;
+            Ok(())
+        })
+    }
+
     // cargo test -- --show-output test_sub_real_smart_home_msg
     #[test]
-    #[ignore]
     fn test_sub_real_smart_home_msg() -> Result<()> {
         init_logging();
         let mut rt = tokio::runtime::Runtime::new()?;
         rt.block_on(async {
-            let mut c = plain_client(
-                &MQTT_CONN.host,
-                MQTT_CONN.port,
-                &MQTT_CONN.username,
-                &MQTT_CONN.password,
-            )?;
+            let broker = Broker::start().await?;
+            let mut c = plain_client(&broker.host(), broker.port(), "user", "password")?;
             c.connect().await?;
 
             // Subscribe
@@ -156,6 +172,19 @@ mod tests {
             let subres = c.subscribe(subopts).await?;
             subres.any_failures()?;
 
+            // smart home publishes encrypted command and waits for acknowledgment
+            let mut smart_home = plain_client(&broker.host(), broker.port(), "user", "password")?;
+            smart_home.connect().await?;
+            let subres = smart_home
+                .subscribe(Subscribe::new(vec![SubscribeTopic {
+                    qos: QoS::AtMostOnce,
+                    topic_path: "garage/toggleConfirm".to_owned(),
+                }]))
+                .await?;
+            subres.any_failures()?;
+            let command = fixtures::encrypted_command(fixtures::command("toggle", "42"))?;
+            publish(command, "garage/toggle".to_owned(), &smart_home).await?;
+
             // Read
             let r = c.read_subscriptions().await?;
             assert_eq!(r.topic(), "garage/toggle");
@@ -166,19 +195,17 @@ mod tests {
             let decrypted_payload = decrypt(&payload, &AES_KEY)?;
             println!("decrypted payload from mqtt {}", decrypted_payload);
 
-            let jwt_svc_verif = JWTService::new(SMART_HOME_ACTION_PUBLIC_KEY.to_owned(), None);
+            let jwt_svc_verif = SMART_HOME_KEYS.verif();
             let claims = jwt_svc_verif.verify(&decrypted_payload, true)?;
             println!("token verified. claims {:#?}", claims);
+            assert_eq!(claims.command, "toggle");
 
             let confirmation_payload = Claims {
                 command: "confirmation".to_owned(),
                 id: claims.id,
                 ..Claims::default()
             };
-            let jwt_svc_signing = JWTService::new(
-                MICROCONTROLLER_PUBLIC_KEY.to_owned(),
-                Some(MICROCONTROLLER_PRIVATE_KEY.to_owned()),
-            );
+            let jwt_svc_signing = MICROCONTROLLER_KEYS.signing();
             let confirmation_token = jwt_svc_signing.sign(confirmation_payload)?;
             println!("acknowledgment prepared {}", confirmation_token);
 
@@ -191,13 +218,19 @@ mod tests {
             c.publish(&p).await?;
             println!("acknowledgment sent!");
 
-            let mut gpio = gpio::Gpio::new()?;
-            info!("setting pin high");
-            gpio.set_pin_high();
-            delay_for(Duration::from_millis(1000)).await;
-            gpio.set_pin_low();
-            info!("setting pin low");
+            let gpio = gpio::Gpio::new(&GPIO::default())?;
+            info!("pulsing relay");
+            gpio.relay().pulse(Duration::from_millis(100)).await;
+            info!("relay released");
+
+            // acknowledgment is verified by smart home
+            let r = smart_home.read_subscriptions().await?;
+            let token = String::from_utf8(r.payload().to_vec())?;
+            let claims = MICROCONTROLLER_KEYS.verif().verify(&token, true)?;
+            assert_eq!(claims.command, "confirmation");
+            assert_eq!(claims.id, "42");
 
+            smart_home.disconnect().await?;
             c.disconnect().await?;
             Ok(())
         })
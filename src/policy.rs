@@ -0,0 +1,229 @@
+use crate::errors::{Error, Result};
+use crate::jwt::Claims;
+use crate::toml::{self, PolicyRule};
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+
+/// Access policy evaluated after token is verified. Each rule denies
+/// listed commands within its time window unless token carries override flag.
+/// Policy with no rules allows everything.
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    commands: Vec<String>,
+    issuers: Vec<String>,
+    days: Vec<Weekday>,
+    /// None means whole day
+    window: Option<(NaiveTime, NaiveTime)>,
+    unless_flag: Option<String>,
+}
+
+impl Rule {
+    fn new(config: &PolicyRule) -> Result<Self> {
+        let days = config
+            .days
+            .iter()
+            .map(|day| {
+                day.parse::<Weekday>()
+                    .map_err(|_| Error::new(format!("invalid day in policy rule: {}", day)))
+            })
+            .collect::<Result<Vec<Weekday>>>()?;
+
+        let window = match (&config.from, &config.to) {
+            (Some(from), Some(to)) => Some((parse_time(from)?, parse_time(to)?)),
+            (None, None) => None,
+            _ => {
+                return Err(Error::new(
+                    "policy rule must define both from and to or none of them".to_owned(),
+                ))
+            }
+        };
+
+        Ok(Rule {
+            commands: config.commands.clone(),
+            issuers: config.issuers.clone(),
+            days,
+            window,
+            unless_flag: config.unless_flag.clone(),
+        })
+    }
+
+    fn applies_to(&self, claims: &Claims, now: &NaiveDateTime) -> bool {
+        if !self.commands.is_empty() && !self.commands.contains(&claims.command) {
+            return false;
+        }
+        if !self.issuers.is_empty() && !self.issuers.contains(&claims.iss) {
+            return false;
+        }
+        if !self.days.is_empty() && !self.days.contains(&now.weekday()) {
+            return false;
+        }
+        match self.window {
+            None => true,
+            // window can span midnight, e.g. 23:00 - 06:00
+            Some((from, to)) if from <= to => now.time() >= from && now.time() < to,
+            Some((from, to)) => now.time() >= from || now.time() < to,
+        }
+    }
+
+    fn overridden(&self, claims: &Claims) -> bool {
+        match &self.unless_flag {
+            Some(flag) => claims.flags.contains(flag),
+            None => false,
+        }
+    }
+}
+
+impl Policy {
+    pub fn new(config: &toml::Policy) -> Result<Self> {
+        let rules = config
+            .rules
+            .iter()
+            .map(Rule::new)
+            .collect::<Result<Vec<Rule>>>()?;
+        Ok(Policy { rules })
+    }
+
+    /// returns Err with human readable reason if command is denied at given (local) time
+    pub fn evaluate(
+        &self,
+        claims: &Claims,
+        now: &NaiveDateTime,
+    ) -> std::result::Result<(), String> {
+        for (idx, rule) in self.rules.iter().enumerate() {
+            if rule.applies_to(claims, now) && !rule.overridden(claims) {
+                return Err(format!(
+                    "command '{}' from '{}' denied by policy rule #{}",
+                    claims.command, claims.iss, idx
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_time(time: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| {
+        Error::new(format!(
+            "invalid time in policy rule: {}, expected HH:MM",
+            time
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn night_policy() -> Result<Policy> {
+        Policy::new(&toml::Policy {
+            rules: vec![PolicyRule {
+                commands: vec!["open".to_owned()],
+                issuers: vec![],
+                days: vec![],
+                from: Some("23:00".to_owned()),
+                to: Some("06:00".to_owned()),
+                unless_flag: Some("night_override".to_owned()),
+            }],
+        })
+    }
+
+    fn at(day: u32, hour: u32, min: u32) -> NaiveDateTime {
+        // 2020-09-07 is monday
+        NaiveDate::from_ymd(2020, 9, day).and_hms(hour, min, 0)
+    }
+
+    fn claims(command: &str, flags: Vec<&str>) -> Claims {
+        Claims {
+            command: command.to_owned(),
+            flags: flags.into_iter().map(|f| f.to_owned()).collect(),
+            ..Claims::default()
+        }
+    }
+
+    // cargo test -- --show-output test_window_over_midnight
+    #[test]
+    fn test_window_over_midnight() -> Result<()> {
+        let policy = night_policy()?;
+        assert!(policy
+            .evaluate(&claims("open", vec![]), &at(7, 22, 59))
+            .is_ok());
+        assert!(policy
+            .evaluate(&claims("open", vec![]), &at(7, 23, 0))
+            .is_err());
+        assert!(policy
+            .evaluate(&claims("open", vec![]), &at(8, 5, 59))
+            .is_err());
+        assert!(policy
+            .evaluate(&claims("open", vec![]), &at(8, 6, 0))
+            .is_ok());
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_other_command_allowed
+    #[test]
+    fn test_other_command_allowed() -> Result<()> {
+        let policy = night_policy()?;
+        assert!(policy
+            .evaluate(&claims("close", vec![]), &at(7, 23, 30))
+            .is_ok());
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_override_flag
+    #[test]
+    fn test_override_flag() -> Result<()> {
+        let policy = night_policy()?;
+        let result = policy.evaluate(&claims("open", vec!["night_override"]), &at(7, 23, 30));
+        assert!(result.is_ok());
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_days_and_issuer
+    #[test]
+    fn test_days_and_issuer() -> Result<()> {
+        let policy = Policy::new(&toml::Policy {
+            rules: vec![PolicyRule {
+                commands: vec![],
+                issuers: vec!["myhome-cc-smarthome-aog".to_owned()],
+                days: vec!["sat".to_owned(), "Sunday".to_owned()],
+                from: None,
+                to: None,
+                unless_flag: None,
+            }],
+        })?;
+        // monday
+        assert!(policy
+            .evaluate(&claims("toggle", vec![]), &at(7, 12, 0))
+            .is_ok());
+        // sunday
+        assert!(policy
+            .evaluate(&claims("toggle", vec![]), &at(13, 12, 0))
+            .is_err());
+
+        let other_issuer = Claims {
+            iss: "someone-else".to_owned(),
+            ..claims("toggle", vec![])
+        };
+        assert!(policy.evaluate(&other_issuer, &at(13, 12, 0)).is_ok());
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_invalid_rule
+    #[test]
+    fn test_invalid_rule() {
+        let policy = Policy::new(&toml::Policy {
+            rules: vec![PolicyRule {
+                commands: vec![],
+                issuers: vec![],
+                days: vec!["someday".to_owned()],
+                from: Some("25:00".to_owned()),
+                to: None,
+                unless_flag: None,
+            }],
+        });
+        assert!(policy.is_err());
+    }
+}
@@ -0,0 +1,282 @@
+use crate::errors::{Error, Result};
+use crate::jwt::{Claims, JWTService};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use rsa::pkcs1::{EncodeRsaPrivateKey, LineEnding};
+use rsa::pkcs8::EncodePublicKey;
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// configuration template, generated keys are filled in
+const TEMPLATE: &str = include_str!("../examples/app_config_example.toml");
+
+pub const PRIVATE_KEY_FILE: &str = "microcontroller-priv.pem";
+pub const PUBLIC_KEY_FILE: &str = "microcontroller-pub.pem";
+pub const AES_KEY_FILE: &str = "aes.key";
+/// smart home public key is provided by the cloud side
+pub const SMART_HOME_KEY_FILE: &str = "smart-home-pub.pem";
+
+/// settings of provision subcommand
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProvisionOptions {
+    /// where keys are written, directory of configuration file by default
+    pub key_dir: Option<String>,
+    /// already available smart home public key
+    pub smart_home_pub_key: Option<String>,
+    /// RSA key size, RS256 requires at least 2048 bits
+    pub bits: usize,
+    /// overwrite existing files
+    pub force: bool,
+}
+
+/// material generated by provision, public key and aes key must be configured on the cloud side
+pub struct Provisioned {
+    pub files: Vec<PathBuf>,
+    pub public_key: String,
+    pub aes_key: String,
+    /// smart home public key referred by configuration
+    pub smart_home_pub_key: PathBuf,
+    /// false if smart home public key is yet to be provided
+    pub smart_home_key_found: bool,
+}
+
+/// Generates microcontroller key pair and aes key, writes them to key directory and writes
+/// configuration referring to them. Secret files are readable by owner only.
+pub fn provision(config_path: &Path, options: &ProvisionOptions) -> Result<Provisioned> {
+    let key_dir = match &options.key_dir {
+        Some(key_dir) => PathBuf::from(key_dir),
+        None => config_path
+            .parent()
+            .map_or(PathBuf::from("."), |dir| dir.to_path_buf()),
+    };
+    fs::create_dir_all(&key_dir)?;
+    // configuration must work regardless of working directory of the controller
+    let key_dir = key_dir.canonicalize()?;
+
+    let (smart_home_pub_key, smart_home_key_found) = match &options.smart_home_pub_key {
+        Some(path) => {
+            let key = fs::read_to_string(path).map_err(|err| {
+                Error::new(format!(
+                    "unable to load smart home public key {}: {}",
+                    path, err
+                ))
+            })?;
+            JWTService::new(key, None).check_keys()?;
+            (Path::new(path).canonicalize()?, true)
+        }
+        None => (key_dir.join(SMART_HOME_KEY_FILE), false),
+    };
+    let private_key_path = key_dir.join(PRIVATE_KEY_FILE);
+    let public_key_path = key_dir.join(PUBLIC_KEY_FILE);
+    let aes_key_path = key_dir.join(AES_KEY_FILE);
+
+    // nothing is written if any file would be overwritten
+    if !options.force {
+        for path in &[
+            config_path,
+            &private_key_path,
+            &public_key_path,
+            &aes_key_path,
+        ] {
+            if path.exists() {
+                return Err(Error::new(format!(
+                    "{} already exists, use --force to overwrite it",
+                    path.display()
+                )));
+            }
+        }
+    }
+
+    let (private_key, public_key) = generate_key_pair(options.bits)?;
+    let aes_key = generate_aes_key();
+    let config = fill_template(
+        TEMPLATE,
+        &[
+            ("aes", "key", "key_file", &aes_key_path),
+            ("smart_home", "pub_key", "pub_key", &smart_home_pub_key),
+            ("microcontroller", "pub_key", "pub_key", &public_key_path),
+            ("microcontroller", "priv_key", "priv_key", &private_key_path),
+        ],
+    );
+
+    write_file(&private_key_path, &private_key, 0o600)?;
+    write_file(&public_key_path, &public_key, 0o644)?;
+    write_file(&aes_key_path, &aes_key, 0o600)?;
+    // configuration holds mqtt password
+    write_file(config_path, &config, 0o600)?;
+
+    Ok(Provisioned {
+        files: vec![
+            config_path.to_path_buf(),
+            private_key_path,
+            public_key_path,
+            aes_key_path,
+        ],
+        public_key,
+        aes_key,
+        smart_home_pub_key,
+        smart_home_key_found,
+    })
+}
+
+/// generates RSA key pair as (PKCS#1 private key, SPKI public key) PEM strings
+pub fn generate_key_pair(bits: usize) -> Result<(String, String)> {
+    let mut rng = rsa::rand_core::OsRng;
+    let private_key = RsaPrivateKey::new(&mut rng, bits)
+        .map_err(|err| Error::new(format!("unable to generate RSA key: {}", err)))?;
+    let public_key = RsaPublicKey::from(&private_key)
+        .to_public_key_pem(LineEnding::LF)
+        .map_err(|err| Error::new(format!("unable to encode public key: {}", err)))?;
+    let private_key = private_key
+        .to_pkcs1_pem(LineEnding::LF)
+        .map_err(|err| Error::new(format!("unable to encode private key: {}", err)))?
+        .to_string();
+
+    // generated pair must be usable for signing replies
+    let jwt_svc = JWTService::new(public_key.clone(), Some(private_key.clone()));
+    jwt_svc.verify(&jwt_svc.sign(Claims::default())?, true)?;
+    Ok((private_key, public_key))
+}
+
+/// aes key is used as is, i.e. 32 characters
+pub fn generate_aes_key() -> String {
+    rand::rngs::OsRng
+        .sample_iter(&Alphanumeric)
+        .take(crate::aes::KEY_LEN)
+        .collect()
+}
+
+/// replaces `key = ...` lines of given sections by `new_key = "path"`, other lines are kept
+fn fill_template(template: &str, values: &[(&str, &str, &str, &Path)]) -> String {
+    let mut section = "";
+    let mut lines = vec![];
+    for line in template.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            section = trimmed.trim_matches(&['[', ']'][..]);
+        }
+        let key = trimmed.split('=').next().unwrap_or("").trim();
+        match values
+            .iter()
+            .find(|value| !trimmed.starts_with('#') && value.0 == section && value.1 == key)
+        {
+            Some((_, _, new_key, path)) => lines.push(format!(
+                "{} = {}",
+                new_key,
+                toml::Value::String(path.display().to_string())
+            )),
+            None => lines.push(line.to_owned()),
+        }
+    }
+    lines.join("\n") + "\n"
+}
+
+fn write_file(path: &Path, contents: &str, mode: u32) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(mode);
+    let mut file = options
+        .open(path)
+        .map_err(|err| Error::new(format!("unable to write {}: {}", path.display(), err)))?;
+    file.write_all(contents.as_bytes())?;
+    // mode given to open applies only to new files
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aes;
+    use crate::keys::Keys;
+    use crate::toml::ApplicationConfiguration;
+
+    // cargo test -- --show-output test_fill_template
+    #[test]
+    fn test_fill_template() -> Result<()> {
+        let config = fill_template(
+            TEMPLATE,
+            &[
+                ("aes", "key", "key_file", Path::new("/keys/aes.key")),
+                (
+                    "microcontroller",
+                    "pub_key",
+                    "pub_key",
+                    Path::new("/keys/pub.pem"),
+                ),
+            ],
+        );
+        assert!(config.contains("key_file = \"/keys/aes.key\""));
+        assert!(!config.contains("<<AES encryption,decryption key>>>"));
+        assert!(config.contains("pub_key = \"/keys/pub.pem\""));
+        // key of other section is kept
+        assert!(config.contains("pub_key = \"/path/to/smart-home/pub-key.pem\""));
+        assert!(config.contains("relay_pin = 4"));
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_provision
+    #[test]
+    fn test_provision() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("garage-provision-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config_path = dir.join("app_config.toml");
+        let mut options = ProvisionOptions {
+            key_dir: None,
+            smart_home_pub_key: None,
+            bits: 2048,
+            force: false,
+        };
+
+        let provisioned = provision(&config_path, &options)?;
+        assert!(!provisioned.smart_home_key_found);
+        assert_eq!(provisioned.files.len(), 4);
+        aes::check_key(&provisioned.aes_key)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |file| fs::metadata(dir.join(file)).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(PRIVATE_KEY_FILE), 0o600);
+            assert_eq!(mode(AES_KEY_FILE), 0o600);
+            assert_eq!(mode(PUBLIC_KEY_FILE), 0o644);
+            assert_eq!(mode("app_config.toml"), 0o600);
+        }
+
+        // smart home would use its own key pair, microcontroller one is good enough here
+        fs::copy(dir.join(PUBLIC_KEY_FILE), dir.join(SMART_HOME_KEY_FILE))?;
+        let config = ApplicationConfiguration::new(config_path.to_str().unwrap())?;
+        assert_eq!(config.aes.key, provisioned.aes_key);
+        assert!(Keys::load(&config).is_ok());
+        // optional hardware and restrictions of the example are left out
+        let door = &config.doors()[0];
+        assert_eq!(door.gpio.open_sensor_pin, None);
+        assert_eq!(door.gpio.closed_sensor_pin, None);
+        assert_eq!(door.gpio.button_pin, None);
+        assert!(config.policy.rules.is_empty());
+        assert_eq!(config.auto_close.alert_after_secs, None);
+        assert!(!config.auto_close.close);
+        assert_eq!(config.lock.unlock_issuer, None);
+        assert_eq!(config.lock.unlock_flag, None);
+
+        // existing files are kept unless forced
+        assert!(provision(&config_path, &options).is_err());
+        options.force = true;
+        let reprovisioned = provision(&config_path, &options)?;
+        assert_ne!(reprovisioned.aes_key, provisioned.aes_key);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+use crate::toml::RateLimit;
+use std::time::{Duration, Instant};
+
+/// result of rate limit evaluation
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    Allowed,
+    /// previous pulse finished recently, contains remaining cooldown
+    CoolingDown(Duration),
+    /// too many commands within configured time window
+    LimitExceeded,
+}
+
+/// Combination of cooldown (minimal pause after each pulse) and token bucket
+/// (max number of commands within time window). Time is always passed by caller
+/// so that limiter can be tested without sleeping.
+pub struct RateLimiter {
+    cooldown: Duration,
+    capacity: f64,
+    /// tokens added per second
+    refill_rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+    cooldown_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimit, now: Instant) -> Self {
+        let capacity = f64::from(config.max_commands);
+        RateLimiter {
+            cooldown: Duration::from_millis(config.cooldown_ms),
+            capacity,
+            refill_rate: capacity / config.window_secs.max(1) as f64,
+            tokens: capacity,
+            last_refill: now,
+            cooldown_until: None,
+        }
+    }
+
+    /// evaluates new command, consumes one token if command is allowed
+    pub fn check(&mut self, now: Instant) -> Decision {
+        if let Some(remaining) = self.remaining_cooldown(now) {
+            return Decision::CoolingDown(remaining);
+        }
+
+        self.refill(now);
+        if self.tokens < 1.0 {
+            return Decision::LimitExceeded;
+        }
+        self.tokens -= 1.0;
+        Decision::Allowed
+    }
+
+    /// starts cooldown, must be called once pulse is finished
+    pub fn pulse_finished(&mut self, now: Instant) {
+        self.cooldown_until = Some(now + self.cooldown);
+    }
+
+    pub fn remaining_cooldown(&self, now: Instant) -> Option<Duration> {
+        match self.cooldown_until {
+            Some(until) if until > now => Some(until - now),
+            _ => None,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.refill_rate).min(self.capacity);
+        self.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(now: Instant) -> RateLimiter {
+        RateLimiter::new(
+            &RateLimit {
+                cooldown_ms: 3000,
+                max_commands: 2,
+                window_secs: 60,
+                queue_during_cooldown: false,
+            },
+            now,
+        )
+    }
+
+    // cargo test -- --show-output test_cooldown
+    #[test]
+    fn test_cooldown() {
+        let start = Instant::now();
+        let mut limiter = limiter(start);
+
+        assert_eq!(limiter.check(start), Decision::Allowed);
+        limiter.pulse_finished(start);
+
+        assert_eq!(
+            limiter.check(start + Duration::from_secs(1)),
+            Decision::CoolingDown(Duration::from_secs(2))
+        );
+        assert_eq!(
+            limiter.check(start + Duration::from_secs(3)),
+            Decision::Allowed
+        );
+    }
+
+    // cargo test -- --show-output test_token_bucket
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut limiter = limiter(start);
+
+        assert_eq!(limiter.check(start), Decision::Allowed);
+        assert_eq!(limiter.check(start), Decision::Allowed);
+        assert_eq!(limiter.check(start), Decision::LimitExceeded);
+
+        // one token is refilled every 30 seconds
+        let later = start + Duration::from_secs(29);
+        assert_eq!(limiter.check(later), Decision::LimitExceeded);
+        let later = start + Duration::from_secs(31);
+        assert_eq!(limiter.check(later), Decision::Allowed);
+        assert_eq!(limiter.check(later), Decision::LimitExceeded);
+    }
+
+    // cargo test -- --show-output test_rejected_command_does_not_consume_token
+    #[test]
+    fn test_rejected_command_does_not_consume_token() {
+        let start = Instant::now();
+        let mut limiter = limiter(start);
+
+        assert_eq!(limiter.check(start), Decision::Allowed);
+        limiter.pulse_finished(start);
+        for _ in 0..5 {
+            assert!(matches!(
+                limiter.check(start + Duration::from_secs(1)),
+                Decision::CoolingDown(_)
+            ));
+        }
+        assert_eq!(
+            limiter.check(start + Duration::from_secs(3)),
+            Decision::Allowed
+        );
+    }
+}
@@ -0,0 +1,306 @@
+use lazy_static::lazy_static;
+use log::{debug, error, warn};
+use std::panic;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Once, TryLockError, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::time::delay_for;
+
+/// Output pin driving the relay. Implemented by real (rppal) and dummy pin,
+/// polarity is handled by implementors, i.e. `set_active(true)` always energizes the relay.
+pub trait RelayPin: Send + 'static {
+    fn set_active(&mut self, active: bool);
+    fn is_active(&self) -> bool;
+}
+
+lazy_static! {
+    /// relays released by panic hook, see Relay::install_panic_hook
+    static ref PANIC_RELAYS: Mutex<Vec<Weak<Mutex<dyn RelayPin>>>> = Mutex::new(vec![]);
+}
+
+/// panic hook is shared by all relays
+static PANIC_HOOK: Once = Once::new();
+
+/// Fail-safe wrapper around relay pin. Relay can be energized only via `PulseGuard`
+/// which restores idle level when dropped (e.g. when pulse future is cancelled).
+/// On top of that, watchdog thread releases the relay once `max_on_time` elapses
+/// regardless of what caller does.
+pub struct Relay<P: RelayPin> {
+    pin: Arc<Mutex<P>>,
+    watchdog: Arc<Watchdog>,
+    max_on_time: Duration,
+}
+
+/// deadline of current pulse shared with watchdog thread
+struct Watchdog {
+    state: Mutex<WatchdogState>,
+    changed: Condvar,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum WatchdogState {
+    Idle,
+    /// relay must be released at given instant
+    Armed(Instant),
+    /// relay was dropped, watchdog thread exits
+    Stopped,
+}
+
+impl Watchdog {
+    fn set(&self, state: WatchdogState) {
+        *lock(&self.state) = state;
+        self.changed.notify_one();
+    }
+
+    /// runs in dedicated thread for the whole lifetime of relay
+    fn run<P: RelayPin>(&self, pin: Weak<Mutex<P>>, max_on_time: Duration) {
+        let mut state = lock(&self.state);
+        loop {
+            match *state {
+                WatchdogState::Stopped => return,
+                WatchdogState::Idle => {
+                    state = self
+                        .changed
+                        .wait(state)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+                }
+                WatchdogState::Armed(deadline) => {
+                    let now = Instant::now();
+                    if now < deadline {
+                        state = self
+                            .changed
+                            .wait_timeout(state, deadline - now)
+                            .unwrap_or_else(|poisoned| poisoned.into_inner())
+                            .0;
+                        continue;
+                    }
+                    *state = WatchdogState::Idle;
+                    if let Some(pin) = pin.upgrade() {
+                        warn!(
+                            "relay active for more than {:?}, releasing it forcibly",
+                            max_on_time
+                        );
+                        lock(&pin).set_active(false);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<P: RelayPin> Relay<P> {
+    pub fn new(pin: P, max_on_time: Duration) -> Self {
+        let pin = Arc::new(Mutex::new(pin));
+        let watchdog = Arc::new(Watchdog {
+            state: Mutex::new(WatchdogState::Idle),
+            changed: Condvar::new(),
+        });
+        let weak_pin = Arc::downgrade(&pin);
+        let thread_watchdog = watchdog.clone();
+        thread::Builder::new()
+            .name("relay-watchdog".to_owned())
+            .spawn(move || thread_watchdog.run(weak_pin, max_on_time))
+            .expect("unable to start relay watchdog thread");
+        let relay = Relay {
+            pin,
+            watchdog,
+            max_on_time,
+        };
+        relay.release();
+        relay
+    }
+
+    /// energizes the relay. Relay is released when returned guard is dropped
+    /// or when max on-time elapses, whichever comes first
+    pub fn pulse_guard(&self) -> PulseGuard<'_, P> {
+        // armed first so that watchdog never releases the relay with deadline of previous pulse
+        self.watchdog
+            .set(WatchdogState::Armed(Instant::now() + self.max_on_time));
+        lock(&self.pin).set_active(true);
+        debug!("relay activated");
+        PulseGuard { relay: self }
+    }
+
+    /// energizes the relay for given duration. If returned future is dropped
+    /// before completion relay is released immediately.
+    pub async fn pulse(&self, duration: Duration) {
+        let _guard = self.pulse_guard();
+        delay_for(duration).await;
+    }
+
+    pub fn release(&self) {
+        self.watchdog.set(WatchdogState::Idle);
+        lock(&self.pin).set_active(false);
+        debug!("relay released");
+    }
+
+    pub fn is_active(&self) -> bool {
+        lock(&self.pin).is_active()
+    }
+
+    pub fn pin(&self) -> MutexGuard<'_, P> {
+        lock(&self.pin)
+    }
+
+    /// Registers relay in panic hook which releases all registered relays before previously
+    /// installed hook is invoked. Hook is installed once for all relays and holds weak
+    /// references only, i.e. dropped relays are skipped.
+    pub fn install_panic_hook(&self) {
+        PANIC_HOOK.call_once(|| {
+            let previous_hook = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                release_on_panic();
+                previous_hook(info);
+            }));
+        });
+        let pin: Arc<Mutex<dyn RelayPin>> = self.pin.clone();
+        let pin = Arc::downgrade(&pin);
+        let mut relays = lock(&PANIC_RELAYS);
+        relays.retain(|relay| relay.strong_count() > 0);
+        relays.push(pin);
+    }
+}
+
+impl<P: RelayPin> Drop for Relay<P> {
+    fn drop(&mut self) {
+        self.release();
+        self.watchdog.set(WatchdogState::Stopped);
+    }
+}
+
+/// releases the relay when dropped
+pub struct PulseGuard<'a, P: RelayPin> {
+    relay: &'a Relay<P>,
+}
+
+impl<'a, P: RelayPin> Drop for PulseGuard<'a, P> {
+    fn drop(&mut self) {
+        self.relay.release();
+    }
+}
+
+/// releases all relays registered in panic hook, locks are never waited for in panic hook
+fn release_on_panic() {
+    let relays = match PANIC_RELAYS.try_lock() {
+        Ok(relays) => relays,
+        Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+        Err(TryLockError::WouldBlock) => {
+            error!("unable to release relays in panic hook, registry is locked");
+            return;
+        }
+    };
+    for pin in relays.iter().filter_map(Weak::upgrade) {
+        match pin.try_lock() {
+            Ok(mut pin) => pin.set_active(false),
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner().set_active(false),
+            Err(TryLockError::WouldBlock) => {
+                error!("unable to release relay in panic hook, pin is locked")
+            }
+        }
+    }
+}
+
+/// pin must be released even if some thread panicked while holding the lock
+fn lock<P: ?Sized>(pin: &Mutex<P>) -> MutexGuard<'_, P> {
+    pin.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::Result;
+    use tokio::time::timeout;
+
+    struct DummyPin {
+        active: bool,
+    }
+
+    impl RelayPin for DummyPin {
+        fn set_active(&mut self, active: bool) {
+            self.active = active;
+        }
+
+        fn is_active(&self) -> bool {
+            self.active
+        }
+    }
+
+    fn relay(max_on_time: Duration) -> Relay<DummyPin> {
+        Relay::new(DummyPin { active: true }, max_on_time)
+    }
+
+    // cargo test -- --show-output test_new_releases_pin
+    #[test]
+    fn test_new_releases_pin() {
+        let relay = relay(Duration::from_secs(1));
+        assert!(!relay.is_active());
+    }
+
+    // cargo test -- --show-output test_guard_releases_on_drop
+    #[test]
+    fn test_guard_releases_on_drop() {
+        let relay = relay(Duration::from_secs(10));
+        {
+            let _guard = relay.pulse_guard();
+            assert!(relay.is_active());
+        }
+        assert!(!relay.is_active());
+    }
+
+    // cargo test -- --show-output test_cancelled_pulse_releases_relay
+    #[test]
+    fn test_cancelled_pulse_releases_relay() -> Result<()> {
+        let relay = relay(Duration::from_secs(10));
+        let mut rt = tokio::runtime::Runtime::new()?;
+        rt.block_on(async {
+            let result = timeout(
+                Duration::from_millis(50),
+                relay.pulse(Duration::from_secs(5)),
+            )
+            .await;
+            assert!(result.is_err());
+        });
+        assert!(!relay.is_active());
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_max_on_time_enforced
+    #[test]
+    fn test_max_on_time_enforced() {
+        let relay = relay(Duration::from_millis(50));
+        let guard = relay.pulse_guard();
+        assert!(relay.is_active());
+        thread::sleep(Duration::from_millis(300));
+        assert!(!relay.is_active());
+        drop(guard);
+    }
+
+    // cargo test -- --show-output test_stale_watchdog_ignored
+    #[test]
+    fn test_stale_watchdog_ignored() {
+        let relay = relay(Duration::from_millis(300));
+        drop(relay.pulse_guard());
+        thread::sleep(Duration::from_millis(150));
+        // watchdog of first pulse must not cut the second one short
+        let _guard = relay.pulse_guard();
+        thread::sleep(Duration::from_millis(200));
+        assert!(relay.is_active());
+    }
+
+    // cargo test -- --show-output test_panic_hook_releases_relay
+    #[test]
+    fn test_panic_hook_releases_relay() {
+        let relays = [
+            relay(Duration::from_secs(10)),
+            relay(Duration::from_secs(10)),
+        ];
+        for relay in &relays {
+            relay.install_panic_hook();
+            // guard is leaked so that only panic hook can release the relay
+            std::mem::forget(relay.pulse_guard());
+        }
+        let result = thread::spawn(|| panic!("simulated panic")).join();
+        assert!(result.is_err());
+        assert!(relays.iter().all(|relay| !relay.is_active()));
+    }
+}
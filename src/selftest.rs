@@ -0,0 +1,307 @@
+use crate::door::DoorState;
+use crate::errors::{Error, Result};
+use crate::gpio::Gpio;
+use crate::toml::{ApplicationConfiguration, Door};
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// how often sensors are read while waiting for the door
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// settings of test-relay subcommand
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelayTestOptions {
+    /// door to test, first door by default
+    pub door: Option<String>,
+    pub pulses: u32,
+    /// how long to wait for sensors after each pulse, travel_secs of the door by default
+    pub wait: Option<Duration>,
+    /// pulse without confirmation prompts
+    pub yes: bool,
+}
+
+/// result of single pulse
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// sensors report expected door state
+    Passed,
+    /// sensors did not change
+    NoChange,
+    /// door did not reach the opposite limit position within wait time
+    NotReached,
+    /// no sensor configured, door must be checked by eyes
+    NotVerifiable,
+}
+
+#[derive(Debug)]
+pub struct PulseResult {
+    pub pulse: u32,
+    pub before: DoorState,
+    pub after: DoorState,
+    /// raw (open, closed) sensor levels read after the pulse
+    pub sensors: (Option<bool>, Option<bool>),
+    pub outcome: Outcome,
+}
+
+impl PulseResult {
+    pub fn failed(&self) -> bool {
+        self.outcome == Outcome::NoChange || self.outcome == Outcome::NotReached
+    }
+}
+
+impl fmt::Display for PulseResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let level = |sensor: Option<bool>| match sensor {
+            Some(true) => "active",
+            Some(false) => "inactive",
+            None => "n/a",
+        };
+        let outcome = match self.outcome {
+            Outcome::Passed => "OK",
+            Outcome::NoChange => "FAILED, sensors did not change",
+            Outcome::NotReached => "FAILED, door did not reach limit position",
+            Outcome::NotVerifiable => "no sensors configured, check the door manually",
+        };
+        write!(
+            f,
+            "pulse {}: {} -> {} (open sensor {}, closed sensor {}): {}",
+            self.pulse,
+            self.before.as_str(),
+            self.after.as_str(),
+            level(self.sensors.0),
+            level(self.sensors.1),
+            outcome
+        )
+    }
+}
+
+/// door selected by options, first door by default
+pub fn select_door(config: &ApplicationConfiguration, door_id: Option<&str>) -> Result<Door> {
+    let doors = config.doors();
+    match door_id {
+        Some(door_id) => doors
+            .into_iter()
+            .find(|door| door.id == door_id)
+            .ok_or_else(|| Error::new(format!("unknown door {}", door_id))),
+        None => Ok(doors[0].clone()),
+    }
+}
+
+/// Pulses relay of the door given number of times without MQTT broker. Each pulse must be
+/// confirmed by `confirm` (returning false stops the test), results are passed to `report`
+/// as soon as sensors settle. Returns results of all pulses.
+pub fn test_relay<C, R>(
+    gpio: &Gpio,
+    door: &Door,
+    options: &RelayTestOptions,
+    mut confirm: C,
+    mut report: R,
+) -> Vec<PulseResult>
+where
+    C: FnMut(&str) -> bool,
+    R: FnMut(&PulseResult),
+{
+    let pulse_duration = Duration::from_millis(door.gpio.pulse_ms);
+    let (open_sensor, closed_sensor) = gpio.sensors();
+    let wait = match options.wait {
+        Some(wait) => wait,
+        None if open_sensor.is_some() || closed_sensor.is_some() => {
+            Duration::from_secs(door.gpio.travel_secs)
+        }
+        None => Duration::from_secs(0),
+    };
+
+    let mut results = vec![];
+    for pulse in 1..=options.pulses {
+        let before = gpio.door_state();
+        let prompt = format!(
+            "pulse {}/{}: energize relay of door {} (pin {}, active_low {}) for {} ms, door is {}",
+            pulse,
+            options.pulses,
+            door.id,
+            door.gpio.relay_pin,
+            door.gpio.active_low,
+            door.gpio.pulse_ms,
+            before.as_str()
+        );
+        if !options.yes && !confirm(&prompt) {
+            break;
+        }
+
+        {
+            let _guard = gpio.relay().pulse_guard();
+            thread::sleep(pulse_duration);
+        }
+
+        let after = wait_for_door(gpio, before, wait);
+        let result = PulseResult {
+            pulse,
+            before,
+            after,
+            sensors: gpio.sensors(),
+            outcome: outcome(gpio, before, after),
+        };
+        report(&result);
+        results.push(result);
+    }
+    results
+}
+
+/// polls sensors until door leaves its original state and settles (reaches limit
+/// position if both sensors are configured) or wait time elapses
+fn wait_for_door(gpio: &Gpio, before: DoorState, wait: Duration) -> DoorState {
+    let started = Instant::now();
+    loop {
+        let state = gpio.door_state();
+        let settled = state != before && (!gpio.has_limit_sensors() || state != DoorState::Moving);
+        if settled || started.elapsed() >= wait {
+            return state;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn outcome(gpio: &Gpio, before: DoorState, after: DoorState) -> Outcome {
+    match gpio.sensors() {
+        (None, None) => Outcome::NotVerifiable,
+        _ if after == before => Outcome::NoChange,
+        _ if gpio.has_limit_sensors() && after == DoorState::Moving => Outcome::NotReached,
+        _ => Outcome::Passed,
+    }
+}
+
+#[cfg(all(test, not(all(target_family = "unix", target_arch = "arm"))))]
+mod tests {
+    use super::*;
+    use crate::toml::{Topics, GPIO};
+
+    fn door(gpio: GPIO) -> Door {
+        Door {
+            id: "garage".to_owned(),
+            gpio: GPIO {
+                pulse_ms: 10,
+                ..gpio
+            },
+            topics: Topics::default(),
+        }
+    }
+
+    fn options(pulses: u32) -> RelayTestOptions {
+        RelayTestOptions {
+            door: None,
+            pulses,
+            wait: Some(Duration::from_millis(500)),
+            yes: false,
+        }
+    }
+
+    // cargo test -- --show-output test_relay_limit_sensors
+    #[test]
+    fn test_relay_limit_sensors() -> Result<()> {
+        let door = door(GPIO {
+            open_sensor_pin: Some(17),
+            closed_sensor_pin: Some(27),
+            ..GPIO::default()
+        });
+        let gpio = Gpio::new(&door.gpio)?;
+        let open = gpio.open_sensor().unwrap().clone();
+        let closed = gpio.closed_sensor().unwrap().clone();
+        closed.simulate(true);
+
+        // door moves once relay clicks, second pulse is ignored by the door
+        let mut prompts = vec![];
+        let results = thread::scope(|scope| {
+            scope.spawn(|| {
+                while !gpio.relay().is_active() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                closed.simulate(false);
+                thread::sleep(Duration::from_millis(50));
+                open.simulate(true);
+            });
+            test_relay(
+                &gpio,
+                &door,
+                &options(2),
+                |prompt| {
+                    prompts.push(prompt.to_owned());
+                    true
+                },
+                |result| println!("{}", result),
+            )
+        });
+
+        assert_eq!(prompts.len(), 2);
+        assert!(prompts[0].contains("door is closed"));
+        assert_eq!(results[0].before, DoorState::Closed);
+        assert_eq!(results[0].after, DoorState::Open);
+        assert_eq!(results[0].sensors, (Some(true), Some(false)));
+        assert_eq!(results[0].outcome, Outcome::Passed);
+        assert_eq!(results[1].outcome, Outcome::NoChange);
+        assert!(results[1].failed());
+        assert!(!gpio.relay().is_active());
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_relay_without_sensors
+    #[test]
+    fn test_relay_without_sensors() -> Result<()> {
+        let door = door(GPIO::default());
+        let gpio = Gpio::new(&door.gpio)?;
+
+        let results = test_relay(&gpio, &door, &options(3), |_| true, |_| {});
+        assert_eq!(results.len(), 3);
+        assert!(results
+            .iter()
+            .all(|result| result.outcome == Outcome::NotVerifiable && !result.failed()));
+
+        // declined prompt stops the test
+        let mut answers = vec![true, false].into_iter();
+        let results = test_relay(
+            &gpio,
+            &door,
+            &options(3),
+            |_| answers.next().unwrap(),
+            |_| {},
+        );
+        assert_eq!(results.len(), 1);
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_select_door
+    #[test]
+    fn test_select_door() -> Result<()> {
+        let config = ApplicationConfiguration::from_toml_str(
+            r#"
+            [mqtt]
+            host = "localhost"
+            port = 1883
+            username = "user"
+            password = "pass"
+
+            [aes]
+            key = "key"
+
+            [smart_home]
+            pub_key = "smart-home-pub.pem"
+
+            [microcontroller]
+            pub_key = "pub.pem"
+            priv_key = "priv.pem"
+
+            [[doors]]
+            id = "left"
+            relay_pin = 4
+
+            [[doors]]
+            id = "gate"
+            relay_pin = 17
+        "#,
+        )?;
+        assert_eq!(select_door(&config, None)?.id, "left");
+        assert_eq!(select_door(&config, Some("gate"))?.gpio.relay_pin, 17);
+        assert!(select_door(&config, Some("shed")).is_err());
+        Ok(())
+    }
+}
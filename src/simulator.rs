@@ -0,0 +1,430 @@
+use crate::aes;
+use crate::errors::{Error, Result};
+use crate::jwt::{Claims, JWTService};
+use crate::keys::read_key;
+use crate::mqtt;
+use crate::toml::{ApplicationConfiguration, Topics};
+use log::debug;
+use mqtt_async_client::client::{QoS, Subscribe, SubscribeTopic};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::time::timeout;
+
+/// reply sent by microcontroller before the final one (two-phase confirmation)
+const INTERIM_REPLY: &str = "accepted";
+/// message published on status topic in response to status command
+const STATUS_REPLY: &str = "status";
+
+/// command sent by simulated smart home
+#[derive(Debug, Clone, PartialEq)]
+pub struct SendOptions {
+    /// 'toggle' | 'open' | 'close' | 'lock' | 'unlock' | 'status' | 'history'
+    pub command: String,
+    pub door: Option<String>,
+    pub flags: Vec<String>,
+    pub page: Option<usize>,
+    /// path to smart home private key, it is not part of microcontroller configuration
+    pub private_key: String,
+    /// how long to wait for final reply
+    pub timeout: Duration,
+}
+
+/// verified reply of microcontroller together with round-trip time
+#[derive(Debug)]
+pub struct Reply {
+    pub claims: Claims,
+    pub elapsed: Duration,
+}
+
+impl Reply {
+    /// false for 'accepted' reply which is followed by 'completed' or 'failed'
+    pub fn is_final(&self) -> bool {
+        self.claims.command != INTERIM_REPLY
+    }
+}
+
+/// Acts as smart home fulfillment: signs command by smart home private key, encrypts it,
+/// publishes it on command topic and collects signed replies until the final one arrives
+/// or timeout elapses. Replies are returned in order of arrival. Status command is answered
+/// by status message on status topic, it is collected as the final reply.
+pub async fn send(config: &ApplicationConfiguration, options: &SendOptions) -> Result<Vec<Reply>> {
+    let signing = JWTService::new(
+        read_key(&config.smart_home.pub_key, "smart home public key")?,
+        Some(read_key(&options.private_key, "smart home private key")?),
+    );
+    signing.check_keys()?;
+    let verif = JWTService::new(
+        read_key(
+            &config.microcontroller.pub_key,
+            "microcontroller public key",
+        )?,
+        None,
+    );
+    verif.check_keys()?;
+
+    let topics = topics(config, options.door.as_deref());
+    let claims = build_claims(options, command_id());
+    let id = claims.id.clone();
+    let payload = aes::encrypt(&signing.sign(claims)?, &config.aes.key)?;
+
+    let mut c = mqtt::plain_client(
+        &config.mqtt.host,
+        config.mqtt.port,
+        &config.mqtt.username,
+        &config.mqtt.password,
+    )?;
+    // client retries connection forever (subscription waits for it), simulator should give up
+    let subscribed = timeout(options.timeout, async {
+        c.connect().await?;
+        let mut subscriptions = vec![SubscribeTopic {
+            qos: QoS::AtMostOnce,
+            topic_path: topics.confirm_topic.clone(),
+        }];
+        if topics.status_topic != topics.confirm_topic {
+            subscriptions.push(SubscribeTopic {
+                qos: QoS::AtMostOnce,
+                topic_path: topics.status_topic.clone(),
+            });
+        }
+        let subres = c.subscribe(Subscribe::new(subscriptions)).await?;
+        subres.any_failures()?;
+        Ok::<(), Error>(())
+    })
+    .await;
+    subscribed.map_err(|_| {
+        Error::new(format!(
+            "unable to connect to MQTT server {}:{} within {} s",
+            config.mqtt.host,
+            config.mqtt.port,
+            options.timeout.as_secs()
+        ))
+    })??;
+
+    debug!("sending command {} to {}", id, topics.command_topic);
+    let started = Instant::now();
+    mqtt::publish(payload, topics.command_topic.clone(), &c).await?;
+
+    let mut replies: Vec<Reply> = vec![];
+    while !replies.last().is_some_and(Reply::is_final) {
+        let remaining = match options.timeout.checked_sub(started.elapsed()) {
+            Some(remaining) => remaining,
+            None => break,
+        };
+        let r = match timeout(remaining, mqtt::read_subscriptions(&mut c)).await {
+            Ok(r) => r?,
+            Err(_) => break,
+        };
+        // anybody can publish on confirm topic, such messages must not end the run
+        let claims = match String::from_utf8(r.payload().to_vec())
+            .map_err(Error::from)
+            .and_then(|token| verif.verify(&token, true))
+        {
+            Ok(claims) => claims,
+            Err(err) => {
+                debug!(
+                    "ignoring message on {} not signed by microcontroller: {}",
+                    r.topic(),
+                    err
+                );
+                continue;
+            }
+        };
+        // replies to commands of others are published on the same topic,
+        // retained status was published for earlier command
+        if claims.id != id {
+            debug!("ignoring reply {} to other command", claims.id);
+            continue;
+        }
+        // status of every door is published, possibly on the same topic
+        if claims.command == STATUS_REPLY && options.door.is_some() && claims.door != options.door {
+            debug!("ignoring status of door {:?}", claims.door);
+            continue;
+        }
+        replies.push(Reply {
+            claims,
+            elapsed: started.elapsed(),
+        });
+    }
+
+    c.disconnect().await?;
+    Ok(replies)
+}
+
+/// topics of door addressed by command, first door if door is not given (or unknown,
+/// so that controller can be asked for unknown door)
+fn topics(config: &ApplicationConfiguration, door_id: Option<&str>) -> Topics {
+    let doors = config.doors();
+    door_id
+        .and_then(|door_id| doors.iter().find(|door| door.id == door_id))
+        .unwrap_or(&doors[0])
+        .topics
+        .clone()
+}
+
+/// unique command id so that controller does not drop command as replay
+fn command_id() -> String {
+    let millis = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    format!("sim-{}", millis)
+}
+
+fn build_claims(options: &SendOptions, id: String) -> Claims {
+    Claims {
+        command: options.command.clone(),
+        id,
+        flags: options.flags.clone(),
+        door: options.door.clone(),
+        page: options.page,
+        ..Claims::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker::Broker;
+    use crate::fixtures::{self, AES_KEY, MICROCONTROLLER_KEYS, SMART_HOME_KEYS};
+    use std::fs;
+
+    const CONFIG: &str = r#"
+        [mqtt]
+        host = "localhost"
+        port = 1883
+        username = "user"
+        password = "pass"
+
+        [aes]
+        key = "key"
+
+        [smart_home]
+        pub_key = "smart-home-pub.pem"
+
+        [microcontroller]
+        pub_key = "pub.pem"
+        priv_key = "priv.pem"
+
+        [[doors]]
+        id = "left"
+        relay_pin = 4
+
+        [[doors]]
+        id = "gate"
+        relay_pin = 17
+        command_topic = "gate/toggle"
+        confirm_topic = "gate/toggleConfirm"
+    "#;
+
+    fn options(command: &str, door: Option<&str>) -> SendOptions {
+        SendOptions {
+            command: command.to_owned(),
+            door: door.map(|door| door.to_owned()),
+            flags: vec!["pin_confirmed".to_owned()],
+            page: None,
+            private_key: "smart-home-priv.pem".to_owned(),
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    // cargo test -- --show-output test_build_claims
+    #[test]
+    fn test_build_claims() {
+        let claims = build_claims(&options("unlock", Some("gate")), "sim-1".to_owned());
+        assert_eq!(claims.command, "unlock");
+        assert_eq!(claims.id, "sim-1");
+        assert_eq!(claims.door, Some("gate".to_owned()));
+        assert_eq!(claims.flags, vec!["pin_confirmed".to_owned()]);
+        assert_eq!(claims.aud, Claims::default().aud);
+        assert_ne!(command_id(), "");
+    }
+
+    // cargo test -- --show-output test_topics
+    #[test]
+    fn test_topics() -> Result<()> {
+        let config = ApplicationConfiguration::from_toml_str(CONFIG)?;
+        assert_eq!(topics(&config, Some("gate")).command_topic, "gate/toggle");
+        assert_eq!(
+            topics(&config, Some("gate")).confirm_topic,
+            "gate/toggleConfirm"
+        );
+        assert_eq!(
+            topics(&config, Some("left")).command_topic,
+            mqtt::TOGGLE_TOPIC
+        );
+        assert_eq!(topics(&config, None).command_topic, mqtt::TOGGLE_TOPIC);
+        assert_eq!(
+            topics(&config, Some("shed")).confirm_topic,
+            mqtt::TOGGLE_CONFIRM_TOPIC
+        );
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_is_final
+    #[test]
+    fn test_is_final() {
+        let reply = |command: &str| Reply {
+            claims: Claims {
+                command: command.to_owned(),
+                ..Claims::default()
+            },
+            elapsed: Duration::from_millis(1),
+        };
+        assert!(!reply("accepted").is_final());
+        assert!(reply("completed").is_final());
+        assert!(reply("confirmation").is_final());
+        assert!(reply("rate_limited").is_final());
+    }
+
+    /// key files written to temporary directory, removed on drop
+    struct KeyFiles {
+        dir: std::path::PathBuf,
+        smart_home_pub: String,
+        smart_home_priv: String,
+        pub_key: String,
+    }
+
+    impl KeyFiles {
+        fn write(test: &str) -> Result<Self> {
+            let dir = std::env::temp_dir().join(format!(
+                "garage-simulator-{}-{}",
+                test,
+                std::process::id()
+            ));
+            fs::create_dir_all(&dir)?;
+            let write = |name: &str, content: &str| -> Result<String> {
+                let path = dir.join(name);
+                fs::write(&path, content)?;
+                Ok(path.to_str().unwrap().to_owned())
+            };
+            Ok(KeyFiles {
+                smart_home_pub: write("smart-home-pub.pem", &SMART_HOME_KEYS.public_key)?,
+                smart_home_priv: write("smart-home-priv.pem", &SMART_HOME_KEYS.private_key)?,
+                pub_key: write("pub.pem", &MICROCONTROLLER_KEYS.public_key)?,
+                dir,
+            })
+        }
+
+        fn config(&self, broker: &Broker) -> Result<ApplicationConfiguration> {
+            ApplicationConfiguration::from_toml_str(
+                &CONFIG
+                    .replace("1883", &broker.port().to_string())
+                    .replace("\"localhost\"", &format!("\"{}\"", broker.host()))
+                    .replace("\"key\"", &format!("\"{}\"", *AES_KEY))
+                    .replace(
+                        "\"smart-home-pub.pem\"",
+                        &format!("{:?}", self.smart_home_pub),
+                    )
+                    .replace("\"pub.pem\"", &format!("{:?}", self.pub_key)),
+            )
+        }
+
+        fn options(&self, command: &str, door: Option<&str>) -> SendOptions {
+            SendOptions {
+                private_key: self.smart_home_priv.clone(),
+                ..options(command, door)
+            }
+        }
+    }
+
+    impl Drop for KeyFiles {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// client of simulated microcontroller subscribed to command topic
+    async fn microcontroller(broker: &Broker) -> Result<mqtt_async_client::client::Client> {
+        let mut c = mqtt::plain_client(&broker.host(), broker.port(), "user", "password")?;
+        c.connect().await?;
+        c.subscribe(Subscribe::new(vec![SubscribeTopic {
+            qos: QoS::AtMostOnce,
+            topic_path: mqtt::TOGGLE_TOPIC.to_owned(),
+        }]))
+        .await?
+        .any_failures()?;
+        Ok(c)
+    }
+
+    /// reads command sent by simulator
+    async fn receive_command(c: &mut mqtt_async_client::client::Client) -> Result<Claims> {
+        let r = mqtt::read_subscriptions(c).await?;
+        let token = aes::decrypt(&String::from_utf8(r.payload().to_vec())?, &AES_KEY)?;
+        SMART_HOME_KEYS.verif().verify(&token, true)
+    }
+
+    fn commands(replies: &[Reply]) -> Vec<&str> {
+        replies
+            .iter()
+            .map(|reply| reply.claims.command.as_str())
+            .collect()
+    }
+
+    // cargo test -- --show-output test_send_ignores_unsigned_reply
+    #[test]
+    fn test_send_ignores_unsigned_reply() -> Result<()> {
+        let keys = KeyFiles::write("unsigned")?;
+        let mut rt = tokio::runtime::Runtime::new()?;
+        rt.block_on(async {
+            let broker = Broker::start().await?;
+            let config = keys.config(&broker)?;
+
+            // microcontroller replying with garbage first
+            let mut c = microcontroller(&broker).await?;
+            let responder = async move {
+                let command = receive_command(&mut c).await?;
+                let topic = mqtt::TOGGLE_CONFIRM_TOPIC.to_owned();
+                mqtt::publish("garbage".to_owned(), topic.clone(), &c).await?;
+                let reply = MICROCONTROLLER_KEYS
+                    .signing()
+                    .sign(fixtures::command("completed", &command.id))?;
+                mqtt::publish(reply, topic, &c).await?;
+                Ok::<(), Error>(())
+            };
+
+            let options = keys.options("toggle", None);
+            let (replies, responded) = tokio::join!(send(&config, &options), responder);
+            responded?;
+            assert_eq!(commands(&replies?), vec!["completed"]);
+            Ok(())
+        })
+    }
+
+    // cargo test -- --show-output test_send_status
+    #[test]
+    fn test_send_status() -> Result<()> {
+        let keys = KeyFiles::write("status")?;
+        let mut rt = tokio::runtime::Runtime::new()?;
+        rt.block_on(async {
+            let broker = Broker::start().await?;
+            let config = keys.config(&broker)?;
+            let status = |id: &str, door: &str| {
+                MICROCONTROLLER_KEYS.signing().sign(Claims {
+                    door: Some(door.to_owned()),
+                    ..fixtures::command("status", id)
+                })
+            };
+
+            let mut c = microcontroller(&broker).await?;
+            // status retained since earlier command is delivered on subscription
+            let topic = mqtt::STATUS_TOPIC.to_owned();
+            mqtt::publish_retained(status("earlier", "left")?, topic.clone(), &c).await?;
+            // controller publishes status of every door, both doors share status topic
+            let responder = async move {
+                let command = receive_command(&mut c).await?;
+                assert_eq!(command.command, "status");
+                mqtt::publish_retained(status(&command.id, "gate")?, topic.clone(), &c).await?;
+                mqtt::publish_retained(status(&command.id, "left")?, topic, &c).await?;
+                Ok::<String, Error>(command.id)
+            };
+
+            let options = keys.options("status", Some("left"));
+            let (replies, responded) = tokio::join!(send(&config, &options), responder);
+            let id = responded?;
+            let replies = replies?;
+            assert_eq!(commands(&replies), vec!["status"]);
+            assert_eq!(replies[0].claims.id, id);
+            assert_eq!(replies[0].claims.door, Some("left".to_owned()));
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,321 @@
+use crate::errors::{Error, Result};
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// version of state file layout, increment whenever layout changes incompatibly
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// lockout (armed) mode, while locked commands which could open the door are refused
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct LockState {
+    pub locked: bool,
+    /// issuer of last lock/unlock command
+    pub changed_by: Option<String>,
+    /// unix timestamp of last change
+    pub changed_at: Option<u64>,
+}
+
+/// persisted state of single door
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct DoorRecord {
+    /// last door state reported by sensors
+    pub last_state: Option<String>,
+    /// id of command (or event) which caused last pulse
+    pub last_pulse_id: Option<String>,
+    /// number of relay pulses
+    pub pulses: u64,
+}
+
+/// safety relevant state which must survive restarts and power cuts
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct State {
+    pub version: u32,
+    pub lock: LockState,
+    /// id of last command received from smart home
+    pub last_command_id: Option<String>,
+    /// ids of received commands with their expiration (unix timestamp),
+    /// command with the same id is never processed twice
+    pub replay_cache: BTreeMap<String, u64>,
+    /// number of commands received from smart home
+    pub commands: u64,
+    pub doors: BTreeMap<String, DoorRecord>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            version: SCHEMA_VERSION,
+            lock: LockState::default(),
+            last_command_id: None,
+            replay_cache: BTreeMap::new(),
+            commands: 0,
+            doors: BTreeMap::new(),
+        }
+    }
+}
+
+impl State {
+    pub fn is_replay(&self, id: &str) -> bool {
+        self.replay_cache.contains_key(id)
+    }
+
+    /// records received command, expired entries are dropped since
+    /// expired tokens are rejected by verification anyway
+    pub fn remember_command(&mut self, id: &str, exp: u64, now: u64) {
+        self.replay_cache.retain(|_, entry_exp| *entry_exp >= now);
+        self.replay_cache.insert(id.to_owned(), exp);
+        self.last_command_id = Some(id.to_owned());
+        self.commands += 1;
+    }
+
+    pub fn door(&mut self, id: &str) -> &mut DoorRecord {
+        self.doors.entry(id.to_owned()).or_default()
+    }
+}
+
+/// State persisted in json file. File is loaded at startup and written
+/// (see write_atomically) whenever state changes.
+pub struct StateStore {
+    path: PathBuf,
+    state: State,
+}
+
+impl StateStore {
+    /// Loads state from given file, missing file means fresh state. File which cannot be
+    /// parsed (e.g. damaged by power cut) is moved aside and fresh state is used instead,
+    /// only file written by newer version is refused.
+    pub fn load(path: &str) -> Result<Self> {
+        let path = PathBuf::from(path);
+        if !path.exists() {
+            return Ok(StateStore {
+                path,
+                state: State::default(),
+            });
+        }
+        let content = fs::read(&path)?;
+        check_version(&content)?;
+        let state = match parse(&content) {
+            Ok(state) => state,
+            Err(err) => {
+                let corrupt_path = move_aside(&path)?;
+                error!(
+                    "state file {} is corrupt ({}), moved to {}, starting with default state",
+                    path.display(),
+                    err,
+                    corrupt_path.display()
+                );
+                State::default()
+            }
+        };
+        Ok(StateStore { path, state })
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// applies change to the state and writes it to disk if anything changed
+    pub fn update<F: FnOnce(&mut State)>(&mut self, change: F) -> Result<()> {
+        let mut state = self.state.clone();
+        change(&mut state);
+        if state == self.state {
+            return Ok(());
+        }
+
+        write_atomically(&self.path, serde_json::to_string_pretty(&state)?.as_bytes())?;
+
+        self.state = state;
+        Ok(())
+    }
+}
+
+/// Writes file so that power cut leaves either previous or new content behind: content
+/// is flushed to disk in temporary file, which is then renamed over the target, and
+/// the rename itself is flushed by syncing the directory. Temporary file name is the whole
+/// target name with .tmp suffix, i.e. garage.json and garage.log do not share it.
+pub(crate) fn write_atomically(path: &Path, content: &[u8]) -> Result<()> {
+    let mut tmp_name = path.file_name().map(OsString::from).unwrap_or_default();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp_path, path)?;
+    sync_dir(path)
+}
+
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+// directories cannot be opened (and synced) on windows
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// renames damaged file to <name>.corrupt so that it can be inspected later
+fn move_aside(path: &Path) -> Result<PathBuf> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".corrupt");
+    let corrupt_path = path.with_file_name(name);
+    fs::rename(path, &corrupt_path)?;
+    Ok(corrupt_path)
+}
+
+/// file written by newer version must not be overwritten, damaged file is detected by parse
+fn check_version(content: &[u8]) -> Result<()> {
+    let version = serde_json::from_slice::<Value>(content)
+        .ok()
+        .and_then(|value| value.get("version").and_then(Value::as_u64));
+    match version {
+        Some(version) if version > SCHEMA_VERSION as u64 => Err(Error::new(format!(
+            "state file version {} is newer than supported version {}",
+            version, SCHEMA_VERSION
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn parse(content: &[u8]) -> Result<State> {
+    Ok(serde_json::from_slice(content)?)
+}
+
+/// current unix timestamp
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("garage-state-{}-{}.json", name, std::process::id()))
+    }
+
+    // cargo test -- --show-output test_state_survives_restart
+    #[test]
+    fn test_state_survives_restart() -> Result<()> {
+        let path = temp_path("restart");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let mut store = StateStore::load(path)?;
+        assert_eq!(store.state(), &State::default());
+        store.update(|state| {
+            state.lock.locked = true;
+            state.remember_command("123", 1000, 900);
+            state.door("garage").pulses += 1;
+        })?;
+
+        let state = StateStore::load(path)?.state().clone();
+        assert!(state.lock.locked);
+        assert!(state.is_replay("123"));
+        assert_eq!(state.last_command_id, Some("123".to_owned()));
+        assert_eq!(state.doors["garage"].pulses, 1);
+
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_unchanged_state_not_written
+    #[test]
+    fn test_unchanged_state_not_written() -> Result<()> {
+        let path = temp_path("unchanged");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let mut store = StateStore::load(path)?;
+        store.update(|_| {})?;
+        assert!(!PathBuf::from(path).exists());
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_write_atomically
+    #[test]
+    fn test_write_atomically() -> Result<()> {
+        let dir = env::temp_dir().join(format!("garage-atomic-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        // state and history file of the same name must not share temporary file
+        fs::write(dir.join("garage.tmp"), "other")?;
+        write_atomically(&dir.join("garage.json"), b"state")?;
+        write_atomically(&dir.join("garage.log"), b"history")?;
+        assert_eq!(fs::read_to_string(dir.join("garage.json"))?, "state");
+        assert_eq!(fs::read_to_string(dir.join("garage.log"))?, "history");
+        assert_eq!(fs::read_to_string(dir.join("garage.tmp"))?, "other");
+        assert!(!dir.join("garage.json.tmp").exists());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_replay_cache_expiry
+    #[test]
+    fn test_replay_cache_expiry() {
+        let mut state = State::default();
+        state.remember_command("1", 100, 40);
+        state.remember_command("2", 160, 100);
+        assert!(state.is_replay("1"));
+        state.remember_command("3", 220, 101);
+        assert!(!state.is_replay("1"));
+        assert!(state.is_replay("2"));
+        assert_eq!(state.commands, 3);
+    }
+
+    // cargo test -- --show-output test_unsupported_version
+    #[test]
+    fn test_unsupported_version() {
+        assert!(check_version(br#"{"version": 1, "commands": 5}"#).is_ok());
+        assert!(parse(br#"{"version": 1, "commands": 5}"#).is_ok());
+        assert!(check_version(br#"{"version": 99}"#).is_err());
+        assert!(check_version(br#"{"version": 1, "comm"#).is_ok());
+        assert!(parse(br#"{"version": 1, "comm"#).is_err());
+    }
+
+    // cargo test -- --show-output test_corrupt_file_moved_aside
+    #[test]
+    fn test_corrupt_file_moved_aside() -> Result<()> {
+        let path = temp_path("corrupt");
+        let corrupt_path = PathBuf::from(format!("{}.corrupt", path.display()));
+        let path = path.to_str().unwrap();
+        // power cut during write may leave truncated (or empty) file behind
+        fs::write(path, r#"{"version": 1, "lock": {"loc"#)?;
+
+        let mut store = StateStore::load(path)?;
+        assert_eq!(store.state(), &State::default());
+        assert_eq!(
+            fs::read_to_string(&corrupt_path)?,
+            r#"{"version": 1, "lock": {"loc"#
+        );
+        store.update(|state| state.commands += 1)?;
+        assert_eq!(StateStore::load(path)?.state().commands, 1);
+
+        fs::write(path, "")?;
+        assert_eq!(StateStore::load(path)?.state(), &State::default());
+        assert!(!PathBuf::from(path).exists());
+
+        fs::remove_file(&corrupt_path)?;
+        Ok(())
+    }
+}
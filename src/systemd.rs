@@ -0,0 +1,231 @@
+use crate::errors::{Error, Result};
+use log::{debug, warn};
+use std::env;
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+#[cfg(unix)]
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::time::{Duration, Instant};
+
+/// Notifies systemd about state of the service (see sd_notify(3)) when started as `Type=notify`
+/// service, i.e. when NOTIFY_SOCKET is set. Otherwise all notifications are ignored.
+/// Notifications are best effort, failures are only logged.
+pub struct Notifier {
+    #[cfg(unix)]
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    /// how often watchdog must be pinged, half of WatchdogSec
+    watchdog_interval: Option<Duration>,
+    last_ping: Option<Instant>,
+}
+
+impl Notifier {
+    /// notifier configured by NOTIFY_SOCKET, WATCHDOG_USEC and WATCHDOG_PID set by systemd
+    pub fn from_env() -> Self {
+        let var = |name| env::var(name).ok();
+        let notifier = Notifier::new(
+            var("NOTIFY_SOCKET").as_deref(),
+            var("WATCHDOG_USEC").as_deref(),
+            var("WATCHDOG_PID").as_deref(),
+        );
+        match notifier {
+            Ok(notifier) => notifier,
+            Err(err) => {
+                warn!("systemd notifications disabled: {}", err);
+                Notifier::disabled()
+            }
+        }
+    }
+
+    fn disabled() -> Self {
+        Notifier {
+            #[cfg(unix)]
+            socket: None,
+            watchdog_interval: None,
+            last_ping: None,
+        }
+    }
+
+    fn new(
+        notify_socket: Option<&str>,
+        watchdog_usec: Option<&str>,
+        watchdog_pid: Option<&str>,
+    ) -> Result<Self> {
+        let mut notifier = Notifier::disabled();
+        let notify_socket = match notify_socket {
+            Some(notify_socket) if !notify_socket.is_empty() => notify_socket,
+            _ => return Ok(notifier),
+        };
+
+        #[cfg(unix)]
+        {
+            notifier.socket = Some((UnixDatagram::unbound()?, socket_addr(notify_socket)?));
+        }
+        #[cfg(not(unix))]
+        let _ = notify_socket;
+
+        // watchdog is meant for other process if pid does not match
+        let for_us = watchdog_pid.is_none_or(|pid| pid.parse() == Ok(process::id()));
+        if let (Some(usec), true) = (watchdog_usec, for_us) {
+            let usec: u64 = usec
+                .parse()
+                .map_err(|_| Error::new(format!("invalid WATCHDOG_USEC {}", usec)))?;
+            if usec > 0 {
+                notifier.watchdog_interval = Some(Duration::from_micros(usec) / 2);
+            }
+        }
+        debug!(
+            "systemd notifications enabled, watchdog interval {:?}",
+            notifier.watchdog_interval
+        );
+        Ok(notifier)
+    }
+
+    /// controller is connected and subscribed, i.e. it is ready to process commands
+    pub fn ready(&self, status: &str) {
+        self.notify(&format!("READY=1\nSTATUS={}", status));
+    }
+
+    /// configuration is being reloaded, must be followed by ready
+    pub fn reloading(&self) {
+        self.notify("RELOADING=1");
+    }
+
+    /// graceful shutdown started
+    pub fn stopping(&self, status: &str) {
+        self.notify(&format!("STOPPING=1\nSTATUS={}", status));
+    }
+
+    /// free form status shown by systemctl status
+    pub fn status(&self, status: &str) {
+        self.notify(&format!("STATUS={}", status));
+    }
+
+    /// pings watchdog if half of watchdog timeout elapsed since the last ping,
+    /// must be called from main loop so that hung loop is detected
+    pub fn watchdog(&mut self, now: Instant) {
+        let interval = match self.watchdog_interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        if self
+            .last_ping
+            .is_some_and(|last_ping| now.duration_since(last_ping) < interval)
+        {
+            return;
+        }
+        self.notify("WATCHDOG=1");
+        self.last_ping = Some(now);
+    }
+
+    /// main loop must not wait for new messages longer, otherwise watchdog would not be pinged in time
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog_interval
+    }
+
+    fn notify(&self, state: &str) {
+        if let Err(err) = self.send(state) {
+            warn!("unable to notify systemd: {}", err);
+        }
+    }
+
+    #[cfg(unix)]
+    fn send(&self, state: &str) -> Result<()> {
+        if let Some((socket, addr)) = &self.socket {
+            socket.send_to_addr(state.as_bytes(), addr)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn send(&self, _state: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// NOTIFY_SOCKET is either path or abstract socket name prefixed by @
+#[cfg(unix)]
+fn socket_addr(notify_socket: &str) -> Result<SocketAddr> {
+    if let Some(name) = notify_socket.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        return Ok(SocketAddr::from_abstract_name(name)?);
+        #[cfg(not(target_os = "linux"))]
+        return Err(Error::new(format!(
+            "abstract NOTIFY_SOCKET {} is not supported",
+            name
+        )));
+    }
+    Ok(SocketAddr::from_pathname(notify_socket)?)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn listen(name: &str) -> Result<(UnixDatagram, String)> {
+        let path = env::temp_dir().join(format!("garage-{}-{}.sock", name, process::id()));
+        let _ = fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path)?;
+        socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+        Ok((socket, path.to_str().unwrap().to_owned()))
+    }
+
+    fn recv(socket: &UnixDatagram) -> Result<String> {
+        let mut buffer = [0; 1024];
+        let len = socket.recv(&mut buffer)?;
+        Ok(String::from_utf8(buffer[..len].to_vec())?)
+    }
+
+    // cargo test -- --show-output test_notify
+    #[test]
+    fn test_notify() -> Result<()> {
+        let (socket, path) = listen("notify")?;
+        let mut notifier = Notifier::new(Some(&path), Some("2000000"), None)?;
+        assert_eq!(notifier.watchdog_interval(), Some(Duration::from_secs(1)));
+
+        notifier.ready("connected");
+        assert_eq!(recv(&socket)?, "READY=1\nSTATUS=connected");
+        notifier.status("reconnecting");
+        assert_eq!(recv(&socket)?, "STATUS=reconnecting");
+
+        // watchdog is pinged at most once per interval
+        let start = Instant::now();
+        notifier.watchdog(start);
+        notifier.watchdog(start + Duration::from_millis(500));
+        notifier.watchdog(start + Duration::from_millis(1000));
+        notifier.stopping("shutting down");
+        assert_eq!(recv(&socket)?, "WATCHDOG=1");
+        assert_eq!(recv(&socket)?, "WATCHDOG=1");
+        assert_eq!(recv(&socket)?, "STOPPING=1\nSTATUS=shutting down");
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_watchdog_disabled
+    #[test]
+    fn test_watchdog_disabled() -> Result<()> {
+        let (socket, path) = listen("watchdog")?;
+        // watchdog of other process
+        let mut notifier = Notifier::new(Some(&path), Some("2000000"), Some("1"))?;
+        assert_eq!(notifier.watchdog_interval(), None);
+        notifier.watchdog(Instant::now());
+        notifier.reloading();
+        assert_eq!(recv(&socket)?, "RELOADING=1");
+
+        let pid = process::id().to_string();
+        let notifier = Notifier::new(Some(&path), Some("4000000"), Some(&pid))?;
+        assert_eq!(notifier.watchdog_interval(), Some(Duration::from_secs(2)));
+        assert!(Notifier::new(Some(&path), Some("soon"), None).is_err());
+
+        // not started by systemd
+        let mut notifier = Notifier::new(None, Some("2000000"), None)?;
+        assert_eq!(notifier.watchdog_interval(), None);
+        notifier.watchdog(Instant::now());
+        notifier.ready("connected");
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
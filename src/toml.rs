@@ -1,6 +1,44 @@
-use crate::errors::Result;
+use crate::errors::{Error, Result};
+use crate::jwt;
+use crate::mqtt;
+use log::warn;
+use serde::de::{self, DeserializeOwned, Deserializer};
 use serde::Deserialize;
-use toml;
+use std::collections::HashSet;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::str::FromStr;
+use toml::{self, Value};
+
+/// id of the door when doors are not configured explicitly
+pub const DEFAULT_DOOR_ID: &str = "garage";
+
+/// prefix of environment variables overriding configuration, e.g. GARAGE_MQTT_PASSWORD
+pub const ENV_PREFIX: &str = "GARAGE_";
+
+/// sections which can be overridden by environment variables
+const SECTIONS: &[&str] = &[
+    "mqtt",
+    "aes",
+    "smart_home",
+    "microcontroller",
+    "gpio",
+    "rate_limit",
+    "auto_close",
+    "confirmation",
+    "commands",
+    "lock",
+    "state",
+    "history",
+    "shutdown",
+];
+
+/// arrays of tables, they can be configured in file only
+const ARRAY_SECTIONS: &[&str] = &["policy", "doors"];
+
+/// suffix of keys holding path to file with the actual value, e.g. password_file
+const FILE_SUFFIX: &str = "_file";
 
 /// master configuration file of application
 #[derive(Debug, Deserialize)]
@@ -9,15 +47,47 @@ pub struct ApplicationConfiguration {
     pub aes: AES,
     pub smart_home: SmartHome,
     pub microcontroller: MicroController,
+    #[serde(default)]
+    pub gpio: GPIO,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+    #[serde(default)]
+    pub policy: Policy,
+    #[serde(default)]
+    pub auto_close: AutoClose,
+    #[serde(default)]
+    pub confirmation: Confirmation,
+    #[serde(default)]
+    pub commands: Commands,
+    #[serde(default)]
+    pub lock: Lock,
+    #[serde(default)]
+    pub state: State,
+    #[serde(default)]
+    pub history: History,
+    #[serde(default)]
+    pub shutdown: Shutdown,
+    /// doors controlled by microcontroller, if empty single door defined by gpio section is used
+    #[serde(default)]
+    pub doors: Vec<Door>,
 }
 
 /// defines attributes of mqtt section
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Deserialize, Default, PartialEq)]
 pub struct MQTT {
     pub host: String,
+    #[serde(deserialize_with = "from_str_or_value")]
     pub port: u16,
     pub username: String,
     pub password: String,
+    /// topic of retained online/offline message, offline is published by broker
+    /// as last will when controller disappears
+    #[serde(default = "default_availability_topic")]
+    pub availability_topic: String,
+}
+
+fn default_availability_topic() -> String {
+    mqtt::AVAILABILITY_TOPIC.to_owned()
 }
 
 /// defines attributes of aes section
@@ -30,6 +100,55 @@ pub struct AES {
 #[derive(Debug, Deserialize)]
 pub struct SmartHome {
     pub pub_key: String,
+    /// issuers (iss claim) of accepted commands, e.g. separate issuer allowed to unlock
+    #[serde(default = "default_issuers", deserialize_with = "list_or_string")]
+    pub issuers: Vec<String>,
+}
+
+fn default_issuers() -> Vec<String> {
+    vec![jwt::SMART_HOME_ISSUER.to_owned()]
+}
+
+/// Accepts value of field type (e.g. number in file) or string parsed into it.
+/// Environment overrides are always strings, their type is given by the field.
+fn from_str_or_value<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned + FromStr,
+    T::Err: Display,
+{
+    match Value::deserialize(deserializer)? {
+        Value::String(value) => value
+            .trim()
+            .parse()
+            .map_err(|err| de::Error::custom(format!("invalid value '{}': {}", value, err))),
+        value => value.try_into().map_err(de::Error::custom),
+    }
+}
+
+fn opt_from_str_or_value<'de, D, T>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned + FromStr,
+    T::Err: Display,
+{
+    from_str_or_value(deserializer).map(Some)
+}
+
+/// accepts array of strings or comma separated string (e.g. environment override)
+fn list_or_string<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Value::deserialize(deserializer)? {
+        Value::String(value) => Ok(value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_owned)
+            .collect()),
+        value => value.try_into().map_err(de::Error::custom),
+    }
 }
 
 /// defines attributes of smart_home section
@@ -39,10 +158,670 @@ pub struct MicroController {
     pub priv_key: String,
 }
 
+/// defines attributes of gpio section
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct GPIO {
+    /// relay input pin, rppal uses GPIO.BCM, not GPIO.BOARD numbering
+    #[serde(deserialize_with = "from_str_or_value")]
+    pub relay_pin: u8,
+    /// true if relay is energized by LOW signal (typical for cheap relay boards)
+    #[serde(deserialize_with = "from_str_or_value")]
+    pub active_low: bool,
+    /// how long relay is energized when door is toggled
+    #[serde(deserialize_with = "from_str_or_value")]
+    pub pulse_ms: u64,
+    /// hard limit, relay is released after this time even if pulse was not finished
+    #[serde(deserialize_with = "from_str_or_value")]
+    pub max_on_ms: u64,
+    /// input pin of limit sensor (e.g. reed switch) active when door is fully open
+    #[serde(deserialize_with = "opt_from_str_or_value")]
+    pub open_sensor_pin: Option<u8>,
+    /// input pin of limit sensor active when door is fully closed
+    #[serde(deserialize_with = "opt_from_str_or_value")]
+    pub closed_sensor_pin: Option<u8>,
+    /// true if sensors pull input pins LOW when active, internal pull-up is used in such case
+    #[serde(deserialize_with = "from_str_or_value")]
+    pub sensor_active_low: bool,
+    /// max time door needs to travel between limit positions, used only if both sensors are configured
+    #[serde(deserialize_with = "from_str_or_value")]
+    pub travel_secs: u64,
+    /// input pin of local wall button
+    #[serde(deserialize_with = "opt_from_str_or_value")]
+    pub button_pin: Option<u8>,
+    /// true if button pulls input pin LOW when pressed, internal pull-up is used in such case
+    #[serde(deserialize_with = "from_str_or_value")]
+    pub button_active_low: bool,
+    /// button level must be stable at least this long to be accepted
+    #[serde(deserialize_with = "from_str_or_value")]
+    pub debounce_ms: u64,
+    /// button held at least this long is considered long press
+    #[serde(deserialize_with = "from_str_or_value")]
+    pub long_press_ms: u64,
+    /// command issued by short press
+    pub button_command: String,
+    /// command issued by long press
+    pub long_press_command: String,
+}
+
+impl Default for GPIO {
+    fn default() -> Self {
+        // pin 7 / GPIO04 with active low relay, i.e. setup verified on real raspberry 3b
+        GPIO {
+            relay_pin: 4,
+            active_low: true,
+            pulse_ms: 400,
+            max_on_ms: 2000,
+            open_sensor_pin: None,
+            closed_sensor_pin: None,
+            sensor_active_low: true,
+            travel_secs: 30,
+            button_pin: None,
+            button_active_low: true,
+            debounce_ms: 50,
+            long_press_ms: 2000,
+            button_command: "toggle".to_owned(),
+            long_press_command: "close".to_owned(),
+        }
+    }
+}
+
+/// defines attributes of rate_limit section
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RateLimit {
+    /// minimal pause after each pulse, commands received in the meantime are rejected
+    #[serde(deserialize_with = "from_str_or_value")]
+    pub cooldown_ms: u64,
+    /// max number of commands accepted within window_secs
+    #[serde(deserialize_with = "from_str_or_value")]
+    pub max_commands: u32,
+    #[serde(deserialize_with = "from_str_or_value")]
+    pub window_secs: u64,
+    /// if true, first command received during cooldown is executed once cooldown elapses
+    #[serde(deserialize_with = "from_str_or_value")]
+    pub queue_during_cooldown: bool,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            cooldown_ms: 3000,
+            max_commands: 6,
+            window_secs: 60,
+            queue_during_cooldown: false,
+        }
+    }
+}
+
+/// defines attributes of policy section
+#[derive(Debug, Deserialize, Default)]
+pub struct Policy {
+    #[serde(default)]
+    pub rules: Vec<PolicyRule>,
+}
+
+/// Denies listed commands within time window unless token carries unless_flag.
+/// Empty list (or missing from/to) means the rule applies to everything.
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct PolicyRule {
+    pub commands: Vec<String>,
+    pub issuers: Vec<String>,
+    /// e.g. ["sat", "sun"]
+    pub days: Vec<String>,
+    /// local time, HH:MM
+    pub from: Option<String>,
+    /// local time, HH:MM, can be lower than from, e.g. 23:00 - 06:00
+    pub to: Option<String>,
+    pub unless_flag: Option<String>,
+}
+
+/// defines attributes of auto_close section
+#[derive(Debug, Deserialize, Default, PartialEq)]
+#[serde(default)]
+pub struct AutoClose {
+    /// signed warning is published once door is open longer than this, disabled if not set
+    #[serde(deserialize_with = "opt_from_str_or_value")]
+    pub alert_after_secs: Option<u64>,
+    /// if true, door is closed automatically when warning is published
+    #[serde(deserialize_with = "from_str_or_value")]
+    pub close: bool,
+}
+
+/// defines attributes of confirmation section
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct Confirmation {
+    /// if true (and both limit sensors are configured) command is acknowledged by 'accepted' reply
+    /// immediately and by 'completed' or 'failed' reply once door movement is finished
+    #[serde(deserialize_with = "from_str_or_value")]
+    pub two_phase: bool,
+}
+
+/// defines attributes of commands section
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct Commands {
+    /// what to do with 'open' and 'close' commands when sensors cannot tell the door state
+    pub unknown_state: UnknownStatePolicy,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownStatePolicy {
+    /// pulse the relay anyway, i.e. behave like 'toggle'
+    Pulse,
+    /// reply 'state_unknown' without actuation
+    #[default]
+    Reject,
+}
+
+/// defines attributes of lock section
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct Lock {
+    /// if set, only commands from this issuer can unlock the controller
+    pub unlock_issuer: Option<String>,
+    /// if set, unlock command must carry this flag (e.g. second factor confirmed by smart home)
+    pub unlock_flag: Option<String>,
+}
+
+/// defines attributes of state section
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct State {
+    /// file where controller state (lock, replay cache, door states, counters) is persisted
+    pub file: String,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            file: "garage_state.json".to_owned(),
+        }
+    }
+}
+
+/// defines attributes of history section
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct History {
+    /// file where history entries are kept
+    pub file: String,
+    /// number of most recent entries kept, 0 disables history
+    #[serde(deserialize_with = "from_str_or_value")]
+    pub max_entries: usize,
+    /// number of entries in single history reply
+    #[serde(deserialize_with = "from_str_or_value")]
+    pub page_size: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            file: "garage_history.jsonl".to_owned(),
+            max_entries: 500,
+            page_size: 20,
+        }
+    }
+}
+
+/// defines attributes of shutdown section
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Shutdown {
+    /// how long movements caused by already executed commands are watched before shutdown
+    #[serde(deserialize_with = "from_str_or_value")]
+    pub drain_secs: u64,
+    /// controller exits after this time even if broker does not respond
+    #[serde(deserialize_with = "from_str_or_value")]
+    pub deadline_secs: u64,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            drain_secs: 5,
+            deadline_secs: 10,
+        }
+    }
+}
+
+/// defines attributes of doors array, i.e. single door (or gate) with its own relay, sensors and topics
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Door {
+    pub id: String,
+    #[serde(flatten)]
+    pub gpio: GPIO,
+    #[serde(flatten)]
+    pub topics: Topics,
+}
+
+/// mqtt topics of single door, several doors can share the same topics
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Topics {
+    /// topic where smart home publishes encrypted commands
+    pub command_topic: String,
+    /// topic where microcontroller publishes signed replies
+    pub confirm_topic: String,
+    /// topic where microcontroller publishes signed events
+    pub events_topic: String,
+    /// topic where microcontroller publishes signed (retained) status
+    pub status_topic: String,
+}
+
+impl Default for Topics {
+    fn default() -> Self {
+        Topics {
+            command_topic: mqtt::TOGGLE_TOPIC.to_owned(),
+            confirm_topic: mqtt::TOGGLE_CONFIRM_TOPIC.to_owned(),
+            events_topic: mqtt::EVENTS_TOPIC.to_owned(),
+            status_topic: mqtt::STATUS_TOPIC.to_owned(),
+        }
+    }
+}
+
 impl ApplicationConfiguration {
+    /// loads configuration file, values can be overridden by GARAGE_* environment variables
     pub fn new(toml_path: &str) -> Result<ApplicationConfiguration> {
-        let toml_str = std::fs::read_to_string(toml_path)?;
-        let app_config = toml::from_str::<ApplicationConfiguration>(&toml_str)?;
+        let toml_str = fs::read_to_string(toml_path)?;
+        let mut value = toml::from_str::<Value>(&toml_str)?;
+        apply_env_overrides(&mut value, env::vars())?;
+        ApplicationConfiguration::from_value(value)
+    }
+
+    pub fn from_toml_str(toml_str: &str) -> Result<ApplicationConfiguration> {
+        ApplicationConfiguration::from_value(toml::from_str::<Value>(toml_str)?)
+    }
+
+    fn from_value(mut value: Value) -> Result<ApplicationConfiguration> {
+        resolve_files(&mut value)?;
+        let app_config = value.try_into::<ApplicationConfiguration>()?;
+
+        let mut ids = HashSet::new();
+        for door in &app_config.doors {
+            if !ids.insert(&door.id) {
+                return Err(Error::new(format!("duplicate door id: {}", door.id)));
+            }
+        }
         Ok(app_config)
     }
+
+    /// names of settings which differ in other configuration but are applied only on restart,
+    /// i.e. they are ignored by reload
+    pub fn restart_required(&self, other: &ApplicationConfiguration) -> Vec<&'static str> {
+        let mut changed = vec![];
+        if self.mqtt != other.mqtt {
+            changed.push("mqtt");
+        }
+        if self.doors() != other.doors() {
+            changed.push("gpio/doors");
+        }
+        if self.rate_limit != other.rate_limit {
+            changed.push("rate_limit");
+        }
+        if self.auto_close != other.auto_close {
+            changed.push("auto_close");
+        }
+        if self.state != other.state {
+            changed.push("state");
+        }
+        if self.history.file != other.history.file
+            || self.history.max_entries != other.history.max_entries
+        {
+            changed.push("history.file/max_entries");
+        }
+        changed
+    }
+
+    /// doors to be controlled, i.e. either doors array or single door defined by gpio section
+    pub fn doors(&self) -> Vec<Door> {
+        if !self.doors.is_empty() {
+            return self.doors.clone();
+        }
+        vec![Door {
+            id: DEFAULT_DOOR_ID.to_owned(),
+            gpio: self.gpio.clone(),
+            topics: Topics::default(),
+        }]
+    }
+}
+
+/// Applies GARAGE_<SECTION>_<KEY> variables, e.g. GARAGE_RATE_LIMIT_COOLDOWN_MS.
+/// Section is matched by the longest known section name. Value is inserted as string,
+/// it is converted to type of the field when configuration is deserialized.
+fn apply_env_overrides<I>(config: &mut Value, vars: I) -> Result<()>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    for (name, raw_value) in vars {
+        if !name.starts_with(ENV_PREFIX) {
+            continue;
+        }
+        let rest = &name[ENV_PREFIX.len()..];
+        let section = SECTIONS
+            .iter()
+            .filter(|section| {
+                rest.len() > section.len() + 1
+                    && rest.starts_with(&section.to_uppercase())
+                    && rest[section.len()..].starts_with('_')
+            })
+            .max_by_key(|section| section.len());
+        let section = match section {
+            Some(section) => *section,
+            None => {
+                if let Some(array) = ARRAY_SECTIONS
+                    .iter()
+                    .find(|array| rest.starts_with(&format!("{}_", array.to_uppercase())))
+                {
+                    return Err(Error::new(format!(
+                        "environment variable {} cannot override [{}] section, arrays can be configured in file only",
+                        name, array
+                    )));
+                }
+                warn!("environment variable {} does not match any section", name);
+                continue;
+            }
+        };
+        let key = rest[section.len() + 1..].to_lowercase();
+
+        let root = config
+            .as_table_mut()
+            .ok_or_else(|| Error::new("configuration must be a table".to_owned()))?;
+        let table = root
+            .entry(section.to_owned())
+            .or_insert_with(|| Value::Table(toml::value::Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| Error::new(format!("{} must be a table", section)))?;
+
+        // file variant must not shadow value set by environment and vice versa
+        if let Some(plain_key) = key.strip_suffix(FILE_SUFFIX) {
+            table.remove(plain_key);
+        } else {
+            table.remove(&format!("{}{}", key, FILE_SUFFIX));
+        }
+        table.insert(key, Value::String(raw_value));
+    }
+    Ok(())
+}
+
+/// replaces <key>_file entries of sections by content of referenced files
+fn resolve_files(config: &mut Value) -> Result<()> {
+    let root = match config.as_table_mut() {
+        Some(root) => root,
+        None => return Ok(()),
+    };
+    for section in SECTIONS {
+        let table = match root.get_mut(*section).and_then(Value::as_table_mut) {
+            Some(table) => table,
+            None => continue,
+        };
+        let file_keys: Vec<String> = table
+            .keys()
+            .filter(|key| key.ends_with(FILE_SUFFIX))
+            .cloned()
+            .collect();
+        for file_key in file_keys {
+            let key = file_key[..file_key.len() - FILE_SUFFIX.len()].to_owned();
+            if table.contains_key(&key) {
+                return Err(Error::new(format!(
+                    "{}.{} and {}.{} must not be set both",
+                    section, key, section, file_key
+                )));
+            }
+            let path = match table.remove(&file_key) {
+                Some(Value::String(path)) => path,
+                _ => {
+                    return Err(Error::new(format!(
+                        "{}.{} must be a path",
+                        section, file_key
+                    )))
+                }
+            };
+            table.insert(key, Value::String(read_secret_file(&path)?));
+        }
+    }
+    Ok(())
+}
+
+/// reads secret from file, file must not be accessible by other users
+fn read_secret_file(path: &str) -> Result<String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path)
+            .map_err(|err| Error::new(format!("unable to read secret file {}: {}", path, err)))?
+            .permissions()
+            .mode();
+        if mode & 0o007 != 0 {
+            return Err(Error::new(format!(
+                "secret file {} must not be accessible by others (mode {:o})",
+                path,
+                mode & 0o777
+            )));
+        }
+    }
+    let secret = fs::read_to_string(path)
+        .map_err(|err| Error::new(format!("unable to read secret file {}: {}", path, err)))?;
+    // editors typically add new line at the end of file
+    Ok(secret.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE_CONFIG: &str = r#"
+[mqtt]
+host = "localhost"
+port = 1883
+username = "user"
+password = "password"
+
+[aes]
+key = "546191f3-ac70-43c3-b9ad-a26d8fds"
+
+[smart_home]
+pub_key = "smart-home-pub.pem"
+
+[microcontroller]
+pub_key = "microcontroller-pubkey.pem"
+priv_key = "microcontroller-privkey.pem"
+"#;
+
+    // cargo test -- --show-output test_single_door_from_gpio_section
+    #[test]
+    fn test_single_door_from_gpio_section() -> Result<()> {
+        let config = format!("{}\n[gpio]\nrelay_pin = 17\n", BASE_CONFIG);
+        let config = ApplicationConfiguration::from_toml_str(&config)?;
+        let doors = config.doors();
+        assert_eq!(doors.len(), 1);
+        assert_eq!(doors[0].id, DEFAULT_DOOR_ID);
+        assert_eq!(doors[0].gpio.relay_pin, 17);
+        assert_eq!(doors[0].topics.command_topic, "garage/toggle");
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_doors_array
+    #[test]
+    fn test_doors_array() -> Result<()> {
+        let config = format!(
+            "{}{}",
+            BASE_CONFIG,
+            r#"
+[[doors]]
+id = "left"
+relay_pin = 4
+open_sensor_pin = 17
+
+[[doors]]
+id = "gate"
+relay_pin = 22
+active_low = false
+pulse_ms = 1000
+command_topic = "gate/command"
+confirm_topic = "gate/confirm"
+"#
+        );
+        let config = ApplicationConfiguration::from_toml_str(&config)?;
+        let doors = config.doors();
+        assert_eq!(doors.len(), 2);
+
+        assert_eq!(doors[0].id, "left");
+        assert_eq!(doors[0].gpio.relay_pin, 4);
+        assert_eq!(doors[0].gpio.open_sensor_pin, Some(17));
+        assert_eq!(doors[0].gpio.pulse_ms, 400);
+        assert_eq!(doors[0].topics.command_topic, "garage/toggle");
+
+        assert_eq!(doors[1].id, "gate");
+        assert!(!doors[1].gpio.active_low);
+        assert_eq!(doors[1].gpio.pulse_ms, 1000);
+        assert_eq!(doors[1].topics.command_topic, "gate/command");
+        assert_eq!(doors[1].topics.confirm_topic, "gate/confirm");
+        assert_eq!(doors[1].topics.events_topic, "garage/events");
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_duplicate_door_ids
+    #[test]
+    fn test_duplicate_door_ids() {
+        let config = format!(
+            "{}{}",
+            BASE_CONFIG,
+            r#"
+[[doors]]
+id = "left"
+relay_pin = 4
+
+[[doors]]
+id = "left"
+relay_pin = 22
+"#
+        );
+        assert!(ApplicationConfiguration::from_toml_str(&config).is_err());
+    }
+
+    // cargo test -- --show-output test_restart_required
+    #[test]
+    fn test_restart_required() -> Result<()> {
+        let config = ApplicationConfiguration::from_toml_str(BASE_CONFIG)?;
+        let reloaded = format!(
+            "{}\n[lock]\nunlock_flag = \"pin\"\n[history]\npage_size = 5\n",
+            BASE_CONFIG
+        );
+        let reloaded = ApplicationConfiguration::from_toml_str(&reloaded)?;
+        assert!(config.restart_required(&reloaded).is_empty());
+
+        let reloaded = format!(
+            "{}\n[gpio]\nrelay_pin = 17\n[rate_limit]\ncooldown_ms = 0\n",
+            BASE_CONFIG.replace("port = 1883", "port = 8883")
+        );
+        let reloaded = ApplicationConfiguration::from_toml_str(&reloaded)?;
+        assert_eq!(
+            config.restart_required(&reloaded),
+            vec!["mqtt", "gpio/doors", "rate_limit"]
+        );
+        Ok(())
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    // cargo test -- --show-output test_env_overrides
+    #[test]
+    fn test_env_overrides() -> Result<()> {
+        let mut value = toml::from_str::<Value>(BASE_CONFIG)?;
+        apply_env_overrides(
+            &mut value,
+            vars(&[
+                ("GARAGE_MQTT_PASSWORD", "1234"),
+                ("GARAGE_MQTT_PORT", "8883"),
+                ("GARAGE_RATE_LIMIT_COOLDOWN_MS", "5000"),
+                ("GARAGE_RATE_LIMIT_QUEUE_DURING_COOLDOWN", "true"),
+                ("GARAGE_LOCK_UNLOCK_ISSUER", "42"),
+                ("GARAGE_GPIO_OPEN_SENSOR_PIN", "17"),
+                ("GARAGE_SHUTDOWN_DRAIN_SECS", "2"),
+                ("GARAGE_SMART_HOME_ISSUERS", "aog, admin"),
+                ("GARAGE_UNKNOWN_KEY", "x"),
+                ("PATH", "/usr/bin"),
+            ]),
+        )?;
+        let config = ApplicationConfiguration::from_value(value)?;
+        // value is converted to type of the field, strings stay strings even if they look like number
+        assert_eq!(config.mqtt.password, "1234");
+        assert_eq!(config.mqtt.port, 8883);
+        assert_eq!(config.rate_limit.cooldown_ms, 5000);
+        assert!(config.rate_limit.queue_during_cooldown);
+        assert_eq!(config.lock.unlock_issuer, Some("42".to_owned()));
+        assert_eq!(config.gpio.open_sensor_pin, Some(17));
+        assert_eq!(config.shutdown.drain_secs, 2);
+        assert_eq!(config.smart_home.issuers, vec!["aog", "admin"]);
+
+        // password provided by environment only
+        let mut value =
+            toml::from_str::<Value>(&BASE_CONFIG.replace("password = \"password\"", ""))?;
+        apply_env_overrides(&mut value, vars(&[("GARAGE_MQTT_PASSWORD", "1234")]))?;
+        assert_eq!(
+            ApplicationConfiguration::from_value(value)?.mqtt.password,
+            "1234"
+        );
+
+        let mut value = toml::from_str::<Value>(BASE_CONFIG)?;
+        apply_env_overrides(&mut value, vars(&[("GARAGE_MQTT_PORT", "http")]))?;
+        let err = ApplicationConfiguration::from_value(value).unwrap_err();
+        assert!(err.message.contains("invalid value 'http'"), "{}", err);
+
+        // arrays of tables cannot be overridden
+        for name in &["GARAGE_DOORS_RELAY_PIN", "GARAGE_POLICY_RULES"] {
+            let mut value = toml::from_str::<Value>(BASE_CONFIG)?;
+            let err = apply_env_overrides(&mut value, vars(&[(name, "4")])).unwrap_err();
+            assert!(err.message.contains("file only"), "{}", err);
+        }
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_secret_files
+    #[test]
+    fn test_secret_files() -> Result<()> {
+        let path = env::temp_dir().join(format!("garage-secret-{}", std::process::id()));
+        fs::write(&path, "secret password\n")?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        }
+
+        let mut value = toml::from_str::<Value>(BASE_CONFIG)?;
+        apply_env_overrides(
+            &mut value,
+            vars(&[("GARAGE_MQTT_PASSWORD_FILE", path.to_str().unwrap())]),
+        )?;
+        let config = ApplicationConfiguration::from_value(value)?;
+        assert_eq!(config.mqtt.password, "secret password");
+
+        // the same value must not be set twice
+        let both = BASE_CONFIG.replace(
+            "password = \"password\"",
+            &format!(
+                "password = \"password\"\npassword_file = \"{}\"",
+                path.to_str().unwrap()
+            ),
+        );
+        assert!(ApplicationConfiguration::from_toml_str(&both).is_err());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o644))?;
+            assert!(read_secret_file(path.to_str().unwrap()).is_err());
+        }
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
 }
@@ -0,0 +1,242 @@
+use crate::aes;
+use crate::errors::{Error, Result};
+use crate::jwt::{Claims, JWTService};
+use crate::keys::read_key;
+use crate::state;
+use crate::toml::ApplicationConfiguration;
+use jsonwebtoken::{dangerous_insecure_decode, decode_header};
+use serde_json::Value;
+use std::io::{self, Read};
+
+/// owner of the key pair which signed a token
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Signer {
+    /// commands sent by smart home
+    SmartHome,
+    /// replies, events and status sent by microcontroller
+    Microcontroller,
+}
+
+impl Signer {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Signer::SmartHome => "smart home",
+            Signer::Microcontroller => "microcontroller",
+        }
+    }
+}
+
+/// returns input given on command line, reads stdin if input is missing or '-'
+pub fn read_input(input: Option<&str>) -> Result<String> {
+    match input {
+        Some(input) if input != "-" => Ok(input.to_owned()),
+        _ => {
+            let mut buffer = String::new();
+            io::stdin().read_to_string(&mut buffer)?;
+            Ok(buffer.trim_end_matches(&['\r', '\n'][..]).to_owned())
+        }
+    }
+}
+
+/// encrypts text (typically signed token) by configured aes key
+pub fn encrypt(config: &ApplicationConfiguration, text: &str) -> Result<String> {
+    aes::check_key(&config.aes.key)?;
+    aes::encrypt(text, &config.aes.key)
+}
+
+/// decrypts payload captured on the broker by configured aes key
+pub fn decrypt(config: &ApplicationConfiguration, payload: &str) -> Result<String> {
+    aes::check_key(&config.aes.key)?;
+    aes::decrypt(payload.trim(), &config.aes.key)
+}
+
+/// signs claims given as JSON object by microcontroller private key,
+/// claims which are not given are taken from defaults (issuer, audience, one minute expiry)
+pub fn sign(config: &ApplicationConfiguration, claims: &str) -> Result<String> {
+    let claims = claims_from_json(claims)?;
+    signing_service(config)?.sign(claims)
+}
+
+/// verifies token by public key of given signer, returns its claims as pretty printed JSON
+pub fn verify(
+    config: &ApplicationConfiguration,
+    token: &str,
+    signer: Signer,
+    validate_expiry: bool,
+) -> Result<String> {
+    let claims = verification_service(config, signer)?.verify(token.trim(), validate_expiry)?;
+    Ok(serde_json::to_string_pretty(&claims)?)
+}
+
+/// describes payload captured on the broker: decrypts it (if encrypted), prints header
+/// and claims without verification and then reports which configured key verifies it
+pub fn inspect(config: &ApplicationConfiguration, payload: &str) -> Result<String> {
+    let services: Vec<(Signer, Result<JWTService>)> = [Signer::SmartHome, Signer::Microcontroller]
+        .iter()
+        .map(|signer| (*signer, verification_service(config, *signer)))
+        .collect();
+    inspect_payload(payload, Some(&config.aes.key), &services, state::now())
+}
+
+fn inspect_payload(
+    payload: &str,
+    aes_key: Option<&str>,
+    services: &[(Signer, Result<JWTService>)],
+    now: u64,
+) -> Result<String> {
+    let payload = payload.trim();
+    let mut report = vec![];
+
+    // encrypted payload is <<iv>>:<<data>>, token is <<header>>.<<claims>>.<<signature>>
+    let token = if payload.contains(':') {
+        let key = aes_key.ok_or_else(|| Error::new("aes key not available".to_owned()))?;
+        aes::check_key(key)?;
+        let token = aes::decrypt(payload, key)?;
+        report.push("payload: encrypted, decrypted by configured aes key".to_owned());
+        token
+    } else {
+        report.push("payload: not encrypted".to_owned());
+        payload.to_owned()
+    };
+
+    let header = decode_header(&token)?;
+    report.push(format!(
+        "header: {}",
+        serde_json::to_string_pretty(&header)?
+    ));
+    let claims = dangerous_insecure_decode::<Value>(&token)?.claims;
+    report.push(format!(
+        "claims: {}",
+        serde_json::to_string_pretty(&claims)?
+    ));
+
+    match claims.get("exp").and_then(Value::as_u64) {
+        Some(exp) if exp < now => report.push(format!("expiry: expired {} s ago", now - exp)),
+        Some(exp) => report.push(format!("expiry: expires in {} s", exp - now)),
+        None => report.push("expiry: exp claim missing".to_owned()),
+    }
+
+    for (signer, service) in services {
+        let result = match service {
+            Ok(service) => match service.verify(&token, false) {
+                Ok(_) => "valid".to_owned(),
+                Err(err) => format!("invalid ({})", err),
+            },
+            Err(err) => format!("not checked ({})", err),
+        };
+        report.push(format!("{} key: {}", signer.as_str(), result));
+    }
+
+    Ok(report.join("\n"))
+}
+
+/// builds claims from JSON object, missing claims are taken from Claims::default()
+fn claims_from_json(json: &str) -> Result<Claims> {
+    let mut claims = serde_json::to_value(Claims::default())?;
+    match serde_json::from_str::<Value>(json)? {
+        Value::Object(given) => {
+            let defaults = claims.as_object_mut().unwrap();
+            for (name, value) in given {
+                defaults.insert(name, value);
+            }
+        }
+        _ => return Err(Error::new("claims must be JSON object".to_owned())),
+    }
+    Ok(serde_json::from_value(claims)?)
+}
+
+fn signing_service(config: &ApplicationConfiguration) -> Result<JWTService> {
+    let service = JWTService::new(
+        read_key(
+            &config.microcontroller.pub_key,
+            "microcontroller public key",
+        )?,
+        Some(read_key(
+            &config.microcontroller.priv_key,
+            "microcontroller private key",
+        )?),
+    );
+    service.check_keys()?;
+    Ok(service)
+}
+
+fn verification_service(config: &ApplicationConfiguration, signer: Signer) -> Result<JWTService> {
+    let service = match signer {
+        Signer::SmartHome => JWTService::new(
+            read_key(&config.smart_home.pub_key, "smart home public key")?,
+            None,
+        )
+        .with_issuers(config.smart_home.issuers.clone()),
+        Signer::Microcontroller => JWTService::new(
+            read_key(
+                &config.microcontroller.pub_key,
+                "microcontroller public key",
+            )?,
+            None,
+        ),
+    };
+    service.check_keys()?;
+    Ok(service)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    const AES_KEY: &str = "546191f3-ac70-43c3-b9ad-a26d8fds";
+
+    // cargo test -- --show-output test_claims_from_json
+    #[test]
+    fn test_claims_from_json() -> Result<()> {
+        let claims = claims_from_json(r#"{"command": "open", "id": "42", "door": "left"}"#)?;
+        assert_eq!(claims.command, "open");
+        assert_eq!(claims.id, "42");
+        assert_eq!(claims.door, Some("left".to_owned()));
+        assert_eq!(claims.iss, Claims::default().iss);
+        assert!(claims.exp > claims.iat);
+
+        assert!(claims_from_json(r#"["open"]"#).is_err());
+        assert!(claims_from_json(r#"{"exp": "tomorrow"}"#).is_err());
+        Ok(())
+    }
+
+    // cargo test -- --show-output test_inspect_payload
+    #[test]
+    fn test_inspect_payload() -> Result<()> {
+        let claims = Claims {
+            command: "toggle".to_owned(),
+            id: "1".to_owned(),
+            exp: 1000,
+            ..Claims::default()
+        };
+        // signature is not checked without keys, any algorithm will do
+        let token = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"secret"),
+        )?;
+        let payload = aes::encrypt(&token, AES_KEY)?;
+        let services = vec![(
+            Signer::SmartHome,
+            Err(Error::new(
+                "unable to load smart home public key".to_owned(),
+            )),
+        )];
+
+        let report = inspect_payload(&payload, Some(AES_KEY), &services, 1100)?;
+        println!("{}", report);
+        assert!(report.contains("payload: encrypted"));
+        assert!(report.contains("\"command\": \"toggle\""));
+        assert!(report.contains("\"alg\": \"HS256\""));
+        assert!(report.contains("expiry: expired 100 s ago"));
+        assert!(report.contains("smart home key: not checked"));
+
+        let report = inspect_payload(&token, None, &[], 900)?;
+        assert!(report.contains("payload: not encrypted"));
+        assert!(report.contains("expiry: expires in 100 s"));
+
+        assert!(inspect_payload("not a token", None, &[], 900).is_err());
+        Ok(())
+    }
+}